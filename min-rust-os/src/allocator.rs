@@ -1,19 +1,30 @@
+use crate::memory::{self, vma::KERNEL_VMAS};
 use alloc::alloc::{GlobalAlloc, Layout};
-// use bump::BumpAllocator;
+#[cfg(feature = "alloc-bump")]
+use bump::BumpAllocator;
+use core::fmt;
 use core::ptr::null_mut;
+use core::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "alloc-fixed-block")]
 use fixed_size_block::FixedSizeBlockAllocator;
-// use linked_list::LinkedListAllocator;
+use fixed_size_block::BLOCK_SIZES;
+#[cfg(feature = "alloc-linked-list")]
+use linked_list::LinkedListAllocator;
 // use linked_list_allocator::LockedHeap;
 use x86_64::{
     structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
+        mapper::MapToError, FrameAllocator, Mapper, Page, PageSize, PageTableFlags, Size4KiB,
     },
     VirtAddr,
 };
 
-// pub mod bump;
+pub mod bump;
+pub mod debug;
 pub mod fixed_size_block;
-// pub mod linked_list;
+pub mod linked_list;
+mod page_list;
+pub mod slab;
+pub mod tracking;
 
 pub struct Dummy;
 
@@ -31,35 +42,130 @@ unsafe impl GlobalAlloc for Dummy {
     }
 }
 
-// Temporarily tell the Rust compiler to register an instance of the Dummy allocator as the global heap allocator.
+// The global allocator is picked at compile time with one of the alloc-bump, alloc-linked-list and
+// alloc-fixed-block cargo features. The fixed size block allocator is the default, the other two are mostly
+// useful for comparing the designs. Since the default feature stays on unless it is disabled, select another one
+// with e.g. `cargo test --no-default-features --features alloc-bump`.
+#[cfg(not(any(
+    feature = "alloc-bump",
+    feature = "alloc-linked-list",
+    feature = "alloc-fixed-block"
+)))]
+compile_error!(
+    "select a heap allocator with the alloc-bump, alloc-linked-list or alloc-fixed-block feature"
+);
+
+#[cfg(any(
+    all(feature = "alloc-bump", feature = "alloc-linked-list"),
+    all(feature = "alloc-bump", feature = "alloc-fixed-block"),
+    all(feature = "alloc-linked-list", feature = "alloc-fixed-block")
+))]
+compile_error!(
+    "only one of the alloc-bump, alloc-linked-list and alloc-fixed-block features can be enabled"
+);
+
+#[cfg(feature = "alloc-bump")]
+type HeapAllocator = BumpAllocator;
+#[cfg(feature = "alloc-linked-list")]
+type HeapAllocator = LinkedListAllocator;
+#[cfg(feature = "alloc-fixed-block")]
+type HeapAllocator = FixedSizeBlockAllocator;
+
+// Tell the Rust compiler to register the selected allocator as the global heap allocator.
+// The Locked wrapper synchronises access, which is required because multiple threads could access the
+// ALLOCATOR static at the same time. The allocator starts out without any backing memory, init_heap hands
+// it the heap region.
+// (The Dummy allocator above and the linked_list_allocator crate's LockedHeap can be plugged in here as well.)
 #[global_allocator]
-// The struct is named LockedHeap because it uses the spinning_top::Spinlock type for synchronization.
-// This is required because multiple threads could access the ALLOCATOR static at the same time.
-// Create an allocator without any backing memory.
-// Using bump allocator
-// static ALLOCATOR: Locked<BumpAllocator> = Locked::new(BumpAllocator::new()); // using linked_list_allocator external crate LockedHeap = LockedHeap::empty(); // Dummy = Dummy;
-// static ALLOCATOR: Locked<LinkedListAllocator> = Locked::new(LinkedListAllocator::new());
-static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
-
-// Define a virtual memory region for the heap. Any virtual address range is fine as long as it's
-// not already used for a different memory region. By using `0x_4444_4444_0000` it will be easy
-// to identify a heap pointer.
-pub const HEAP_START: usize = 0x_4444_4444_0000;
-// 100kb for now, can be increased later
+static ALLOCATOR: Tracked<Checked<Locked<HeapAllocator>>> =
+    tracked(checked(Locked::new(HeapAllocator::new())));
+
+// With the heap-debug feature, the allocator is wrapped in a DebugAllocator, which checks every allocation
+// for overflows and double frees
+#[cfg(feature = "heap-debug")]
+type Checked<A> = debug::DebugAllocator<A>;
+#[cfg(feature = "heap-debug")]
+const fn checked<A>(allocator: A) -> Checked<A> {
+    debug::DebugAllocator::new(allocator)
+}
+#[cfg(not(feature = "heap-debug"))]
+type Checked<A> = A;
+#[cfg(not(feature = "heap-debug"))]
+const fn checked<A>(allocator: A) -> Checked<A> {
+    allocator
+}
+
+// With the heap-track feature, every live allocation is recorded by a TrackingAllocator, so that leaks can be
+// found. It is the outermost layer, so that it sees the allocations as the program made them.
+#[cfg(feature = "heap-track")]
+type Tracked<A> = tracking::TrackingAllocator<A>;
+#[cfg(feature = "heap-track")]
+const fn tracked<A>(allocator: A) -> Tracked<A> {
+    tracking::TrackingAllocator::new(allocator)
+}
+#[cfg(not(feature = "heap-track"))]
+type Tracked<A> = A;
+#[cfg(not(feature = "heap-track"))]
+const fn tracked<A>(allocator: A) -> Tracked<A> {
+    allocator
+}
+
+// The virtual memory region for the heap is reserved from the kernel VMA manager in init_heap, so it can't
+// collide with any other region. The kernel randomises the layout at boot, so the heap lives at a different
+// address every time. Without randomisation, it is the first region in the kernel window at
+// `0x_ffff_c000_0000_0000`.
+// The heap starts out with HEAP_SIZE bytes and grows on demand, up to HEAP_MAX_SIZE bytes by default.
+// The whole region for the maximum size is reserved up front, so the heap can always grow in place.
 pub const HEAP_SIZE: usize = 100 * 1024;
+pub const HEAP_MAX_SIZE: usize = 64 * 1024 * 1024;
+// When the heap runs out of memory, it grows by at least this much, so that a series of small allocations
+// doesn't map one page at a time
+const HEAP_GROWTH: usize = 64 * 1024;
+
+// Bounds of the region reserved for the heap. The heap can't grow past its end.
+static HEAP_START: AtomicUsize = AtomicUsize::new(0);
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(0);
+
+// Set the required PRESENT flag and the WRITABLE flag for the heap pages.
+// With these flags both read and write accesses are allowed, which makes sense for heap memory.
+// The heap only holds data, so the NO_EXECUTE flag keeps code injected into it from running.
+const HEAP_FLAGS: PageTableFlags = PageTableFlags::from_bits_truncate(
+    PageTableFlags::PRESENT.bits()
+        | PageTableFlags::WRITABLE.bits()
+        | PageTableFlags::NO_EXECUTE.bits(),
+);
 
 // Map the virtual memory region to the physical memory
 pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
 ) -> Result<(), MapToError<Size4KiB>> {
+    init_heap_with_max_size(mapper, frame_allocator, HEAP_MAX_SIZE)
+}
+
+// Like init_heap, but the heap can grow up to `max_size` bytes instead of HEAP_MAX_SIZE.
+// `max_size` is rounded up to whole pages and must be at least HEAP_SIZE.
+pub fn init_heap_with_max_size(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    max_size: usize,
+) -> Result<(), MapToError<Size4KiB>> {
+    assert!(max_size >= HEAP_SIZE, "the maximum heap size is too small");
+    let max_size = align_up(max_size, Size4KiB::SIZE as usize);
+
+    // Ask the VMA manager for a page aligned range that nothing else uses
+    let heap_start = KERNEL_VMAS
+        .lock()
+        .reserve_anywhere("heap", max_size as u64, Size4KiB::SIZE)
+        .expect("failed to reserve the heap region")
+        .start;
+
     let page_range = {
-        // To create a range of the pages that we want to map, we convert the HEAP_START pointer to a VirtAddr type.
+        // To create a range of the pages that we want to map, we take the start of the reserved region.
         // Then we calculate the heap end address from it by adding the HEAP_SIZE.
         // We want an inclusive bound (the address of the last byte of the heap), so we subtract 1.
         // Next, we convert the addresses into Page types using the containing_address function.
         // Finally, we create a page range from the start and end pages using the Page::range_inclusive function.
-        let heap_start = VirtAddr::new(HEAP_START as u64);
         let heap_end = heap_start + HEAP_SIZE - 1u64;
         let heap_start_page = Page::containing_address(heap_start);
         let heap_end_page = Page::containing_address(heap_end);
@@ -72,26 +178,193 @@ pub fn init_heap(
         let frame = frame_allocator
             .allocate_frame()
             .ok_or(MapToError::FrameAllocationFailed)?;
-        // set the required PRESENT flag and the WRITABLE flag for the page.
-        // With these flags both read and write accesses are allowed, which makes sense for heap memory.
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
         // use the Mapper::map_to method for creating the mapping in the active page table.
         // The method can fail, therefore we use the question mark operator again to forward the error to the caller.
         // On success, the method returns a MapperFlush instance that we can use to update the
         // translation lookaside buffer using the flush method
-        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+        unsafe {
+            mapper
+                .map_to(page, frame, HEAP_FLAGS, frame_allocator)?
+                .flush()
+        };
     }
 
+    HEAP_START.store(heap_start.as_u64() as usize, Ordering::Relaxed);
+    HEAP_LIMIT.store(heap_start.as_u64() as usize + max_size, Ordering::Relaxed);
+
     // Initialize the allocator after creating the heap.
     // use the lock method on the inner spinlock of the LockedHeap type to get an exclusive reference
     // to the wrapped Heap instance, on which we then call the init method with the heap bounds as arguments.
     // It is important that we initialize the heap after mapping the heap pages,
     // since the init function already tries to write to the heap memory.
-    unsafe { ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE) };
+    unsafe {
+        ALLOCATOR
+            .lock()
+            .init(heap_start.as_u64() as usize, HEAP_SIZE)
+    };
 
     Ok(())
 }
 
+// Returns how many bytes of the heap are currently mapped
+pub fn heap_size() -> usize {
+    ALLOCATOR.lock().heap_size()
+}
+
+// Returns how fragmented the free memory of the linked list allocator is
+#[cfg(feature = "alloc-linked-list")]
+pub fn fragmentation() -> linked_list::Fragmentation {
+    ALLOCATOR.lock().fragmentation()
+}
+
+// Hands the unused pages of the block lists back to the fallback allocator, e.g. when memory is tight.
+// Returns how many bytes were released.
+#[cfg(feature = "alloc-fixed-block")]
+pub fn shrink() -> usize {
+    ALLOCATOR.lock().shrink()
+}
+
+// Gives the freed blocks the DebugAllocator holds back to the heap, so that the statistics only count live
+// allocations again
+#[cfg(feature = "heap-debug")]
+pub fn release_quarantine() {
+    ALLOCATOR.release_quarantine()
+}
+
+// Returns a snapshot of the global allocator's counters
+pub fn stats() -> AllocatorStats {
+    ALLOCATOR.lock().stats()
+}
+
+// Counters of a single size class of the allocator. Allocations that are served from a block list count the
+// full block size as in use, because that is what they take up on the heap.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SizeClassStats {
+    pub bytes_in_use: usize,
+    pub allocations: u64,
+    pub deallocations: u64,
+}
+
+impl SizeClassStats {
+    pub const fn new() -> Self {
+        SizeClassStats {
+            bytes_in_use: 0,
+            allocations: 0,
+            deallocations: 0,
+        }
+    }
+}
+
+// Counters of the global allocator, see stats. The totals cover every size class plus the fallback heap.
+// Only the fixed size block allocator has size classes, the bump and linked list allocators count every
+// allocation as a fallback allocation. Failed allocations are not counted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocatorStats {
+    pub bytes_in_use: usize,
+    // The highest value bytes_in_use ever had
+    pub peak_bytes_in_use: usize,
+    pub allocations: u64,
+    pub deallocations: u64,
+    // One entry for each of fixed_size_block::BLOCK_SIZES, in the same order
+    pub size_classes: [SizeClassStats; BLOCK_SIZES.len()],
+    // Allocations that are too large for any block size
+    pub fallback: SizeClassStats,
+}
+
+impl AllocatorStats {
+    pub const fn new() -> Self {
+        AllocatorStats {
+            bytes_in_use: 0,
+            peak_bytes_in_use: 0,
+            allocations: 0,
+            deallocations: 0,
+            size_classes: [SizeClassStats::new(); BLOCK_SIZES.len()],
+            fallback: SizeClassStats::new(),
+        }
+    }
+
+    // Counts an allocation of `size` bytes from the given size class, or from the fallback heap if it is `None`
+    fn record_alloc(&mut self, class: Option<usize>, size: usize) {
+        let class = self.class_mut(class);
+        class.bytes_in_use += size;
+        class.allocations += 1;
+        self.bytes_in_use += size;
+        self.peak_bytes_in_use = self.peak_bytes_in_use.max(self.bytes_in_use);
+        self.allocations += 1;
+    }
+
+    fn record_dealloc(&mut self, class: Option<usize>, size: usize) {
+        let class = self.class_mut(class);
+        class.bytes_in_use -= size;
+        class.deallocations += 1;
+        self.bytes_in_use -= size;
+        self.deallocations += 1;
+    }
+
+    fn class_mut(&mut self, class: Option<usize>) -> &mut SizeClassStats {
+        match class {
+            Some(index) => &mut self.size_classes[index],
+            None => &mut self.fallback,
+        }
+    }
+}
+
+impl fmt::Display for AllocatorStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "in use: {} bytes, peak: {} bytes, {} allocations, {} deallocations",
+            self.bytes_in_use, self.peak_bytes_in_use, self.allocations, self.deallocations
+        )?;
+        for (block_size, class) in BLOCK_SIZES.iter().zip(self.size_classes.iter()) {
+            writeln!(
+                f,
+                "{:>8}: {:>8} bytes in use, {} allocations, {} deallocations",
+                block_size, class.bytes_in_use, class.allocations, class.deallocations
+            )?;
+        }
+        write!(
+            f,
+            "fallback: {:>8} bytes in use, {} allocations, {} deallocations",
+            self.fallback.bytes_in_use, self.fallback.allocations, self.fallback.deallocations
+        )
+    }
+}
+
+// Maps at least `min_size` more bytes of heap memory, starting at `heap_end`, and returns the number of bytes
+// that were mapped. This may be less than `min_size`, or 0, if the heap reached its maximum size or the
+// memory ran out. Allocators that manage some other memory than the heap region (in tests, for example) can't
+// grow and always get 0.
+// The frames come from swap::allocate_frame, so when memory runs low the heap grows by evicting pageable pages.
+// (The initial heap can't do that: swapping needs the heap, so nothing is pageable before init_heap.)
+// The allocator calls this while it is locked, so it must not allocate on the heap itself. It also only tries
+// to lock the mapper: the code that ran out of heap memory might be holding it.
+fn grow_heap(heap_end: usize, min_size: usize) -> usize {
+    let limit = HEAP_LIMIT.load(Ordering::Relaxed);
+    if heap_end < HEAP_START.load(Ordering::Relaxed) {
+        return 0;
+    }
+    let size = align_up(min_size.max(HEAP_GROWTH), Size4KiB::SIZE as usize)
+        .min(limit.saturating_sub(heap_end));
+    if size == 0 {
+        return 0;
+    }
+
+    let first_page: Page = Page::containing_address(VirtAddr::new(heap_end as u64));
+    let pages = Page::range(
+        first_page,
+        first_page + (size / Size4KiB::SIZE as usize) as u64,
+    );
+    let mut mapped = 0;
+    for page in pages {
+        if memory::map_reclaimed_frame(page, HEAP_FLAGS).is_err() {
+            break;
+        }
+        mapped += Size4KiB::SIZE as usize;
+    }
+    mapped
+}
+
 // A wrapper around a spin::Mutex to permit trait implementations
 pub struct Locked<A> {
     inner: spin::Mutex<A>,
@@ -124,7 +397,6 @@ impl<A> Locked<A> {
 //     }
 // }
 
-#[allow(dead_code)]
 // Requires that `align` is a power of two.
 // This method utilizes that the GlobalAlloc trait guarantees that align is always a power of two.
 // This makes it possible to create a bitmask to align the address in a very efficient way.
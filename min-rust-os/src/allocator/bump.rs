@@ -1,4 +1,4 @@
-use super::{align_up, Locked};
+use super::{align_up, AllocatorStats, Locked};
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr;
 
@@ -7,6 +7,7 @@ pub struct BumpAllocator {
     heap_end: usize,
     next: usize,
     allocations: usize,
+    stats: AllocatorStats,
 }
 
 impl BumpAllocator {
@@ -19,6 +20,7 @@ impl BumpAllocator {
             // The allocations field is a simple counter for the active allocations with the goal
             // of resetting the allocator after the last allocation was freed. It is initialized with 0.
             allocations: 0,
+            stats: AllocatorStats::new(),
         }
     }
 
@@ -34,6 +36,16 @@ impl BumpAllocator {
         // the allocation size ("bumped") to ensure that we don't return the same memory region twice.
         self.next = heap_start;
     }
+
+    // Number of bytes the heap currently spans
+    pub fn heap_size(&self) -> usize {
+        self.heap_end - self.heap_start
+    }
+
+    // A copy of the allocation counters
+    pub fn stats(&self) -> AllocatorStats {
+        self.stats
+    }
 }
 
 unsafe impl GlobalAlloc for Locked<BumpAllocator> {
@@ -53,18 +65,26 @@ unsafe impl GlobalAlloc for Locked<BumpAllocator> {
             None => return ptr::null_mut(),
         };
 
+        // If the heap is full, map more memory behind it first
+        if alloc_end > bump.heap_end {
+            let heap_end = bump.heap_end;
+            bump.heap_end += super::grow_heap(heap_end, alloc_end - heap_end);
+        }
+
         if alloc_end > bump.heap_end {
             ptr::null_mut() // out of memory
         } else {
             bump.next = alloc_end;
             bump.allocations += 1;
+            bump.stats.record_alloc(None, layout.size());
             alloc_start as *mut u8
         }
     }
 
-    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {
+    unsafe fn dealloc(&self, _ptr: *mut u8, layout: Layout) {
         let mut bump = self.lock(); // get mutable reference
 
+        bump.stats.record_dealloc(None, layout.size());
         bump.allocations -= 1;
         if bump.allocations == 0 {
             // If the counter reaches 0 again, it means that all allocations were freed again.
@@ -0,0 +1,186 @@
+use crate::serial_println;
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::ops::Deref;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::{ptr, slice};
+use spin::Mutex;
+
+// Size of the red zone behind every allocation, and the minimum size of the one in front of it
+const RED_ZONE: usize = 32;
+// Red zones are filled with this byte, so that writes past either end of an allocation can be noticed on free
+const CANARY: u8 = 0xfd;
+// Freed memory is filled with this byte. A pointer or length made of it in a crash dump hints at a use after free.
+pub const POISON: u8 = 0x6b;
+// The last 8 bytes of the front red zone record whether the block is allocated or was freed. The allocators write
+// their free list nodes to the start of a freed block, but never this far in, so the state survives the free.
+const ALLOCATED: u64 = 0xa110_ca7e_da11_0ca7;
+const FREED: u64 = 0xf7ee_df7e_edf7_eedf;
+// Number of freed blocks that are held back before the wrapped allocator gets them again
+pub const QUARANTINE_BLOCKS: usize = 32;
+
+// Number of heap errors that were found, see errors
+static ERRORS: AtomicUsize = AtomicUsize::new(0);
+
+// Wraps an allocator to catch heap corruption where it happens instead of in some unrelated crash later on.
+// Every allocation is padded with red zones, which are checked when the allocation is freed. Freed memory is
+// poisoned and goes into a quarantine of the last QUARANTINE_BLOCKS frees, so it isn't handed out again right
+// away. When a block leaves the quarantine, the poison is checked to catch writes after the free. Problems are
+// reported over serial together with the Layout of the allocation.
+// Double frees are recognised by the FREED marker at the end of the front red zone. A double free is not passed
+// on to the wrapped allocator, so its free lists stay intact. This only works while the marker survives: it does
+// for blocks in the quarantine, but once the wrapped allocator hands the memory out again, a second free of the
+// old pointer looks like a valid free of the new allocation.
+// The padding makes every allocation at least 64 bytes larger, so the wrapped allocator's statistics count
+// more memory than the program asked for, and the quarantine holds on to some memory on top of that.
+pub struct DebugAllocator<A> {
+    inner: A,
+    quarantine: Mutex<Quarantine>,
+}
+
+impl<A> DebugAllocator<A> {
+    pub const fn new(inner: A) -> Self {
+        DebugAllocator {
+            inner,
+            quarantine: Mutex::new(Quarantine {
+                blocks: [None; QUARANTINE_BLOCKS],
+                next: 0,
+            }),
+        }
+    }
+}
+
+// A ring buffer of freed blocks, as the address and layout the program freed them with. The heap can't keep track
+// of its own blocks, so the size is fixed.
+struct Quarantine {
+    blocks: [Option<(usize, Layout)>; QUARANTINE_BLOCKS],
+    // The slot that is replaced next, which holds the oldest block once the ring is full
+    next: usize,
+}
+
+impl Quarantine {
+    // Adds a block and returns the one that has been in the quarantine the longest, if the ring was full
+    fn push(&mut self, ptr: *mut u8, layout: Layout) -> Option<(usize, Layout)> {
+        let oldest = self.blocks[self.next].replace((ptr as usize, layout));
+        self.next = (self.next + 1) % QUARANTINE_BLOCKS;
+        oldest
+    }
+}
+
+// The wrapped allocator stays accessible, e.g. to initialise it
+impl<A> Deref for DebugAllocator<A> {
+    type Target = A;
+
+    fn deref(&self) -> &A {
+        &self.inner
+    }
+}
+
+// Returns the number of heap errors found so far
+pub fn errors() -> usize {
+    ERRORS.load(Ordering::SeqCst)
+}
+
+// The front red zone must keep the allocation aligned
+fn front_size(layout: &Layout) -> usize {
+    layout.align().max(RED_ZONE)
+}
+
+// The layout of the allocation including its red zones
+fn padded(layout: &Layout) -> Option<Layout> {
+    let size = front_size(layout)
+        .checked_add(layout.size())?
+        .checked_add(RED_ZONE)?;
+    Layout::from_size_align(size, layout.align()).ok()
+}
+
+fn report(problem: &str, ptr: *mut u8, layout: Layout) {
+    ERRORS.fetch_add(1, Ordering::SeqCst);
+    serial_println!("heap-debug: {} at {:p}, {:?}", problem, ptr, layout);
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for DebugAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let padded = match padded(&layout) {
+            Some(padded) => padded,
+            None => return ptr::null_mut(),
+        };
+        let base = self.inner.alloc(padded);
+        if base.is_null() {
+            return base;
+        }
+
+        let front = front_size(&layout);
+        let ptr = base.add(front);
+        ptr::write_bytes(base, CANARY, front - 8);
+        // The allocation is only aligned to layout.align(), which might be less than 8
+        (ptr.sub(8) as *mut u64).write_unaligned(ALLOCATED);
+        ptr::write_bytes(ptr.add(layout.size()), CANARY, RED_ZONE);
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let state = ptr.sub(8) as *mut u64;
+        match state.read_unaligned() {
+            ALLOCATED => {}
+            FREED => {
+                report("double free", ptr, layout);
+                return;
+            }
+            // Either the pointer never came from this allocator, or the end of the front red zone was overwritten
+            _ => {
+                report("free of an unknown or corrupted block", ptr, layout);
+                return;
+            }
+        }
+
+        let front = front_size(&layout);
+        let base = ptr.sub(front);
+        let is_intact = |start: *const u8, len: usize| {
+            slice::from_raw_parts(start, len)
+                .iter()
+                .all(|&byte| byte == CANARY)
+        };
+        if !is_intact(base, front - 8) {
+            report("red zone in front of the block overwritten", ptr, layout);
+        }
+        if !is_intact(ptr.add(layout.size()), RED_ZONE) {
+            report("red zone behind the block overwritten", ptr, layout);
+        }
+
+        ptr::write_bytes(ptr, POISON, layout.size());
+        state.write_unaligned(FREED);
+
+        // The lock is released before the block goes back, since the wrapped allocator has a lock of its own
+        let released = self.quarantine.lock().push(ptr, layout);
+        if let Some((ptr, layout)) = released {
+            self.release(ptr as *mut u8, layout);
+        }
+    }
+}
+
+impl<A: GlobalAlloc> DebugAllocator<A> {
+    // Gives every block in the quarantine back to the wrapped allocator, e.g. before comparing its statistics
+    pub fn release_quarantine(&self) {
+        let blocks = {
+            let mut quarantine = self.quarantine.lock();
+            quarantine.next = 0;
+            core::mem::replace(&mut quarantine.blocks, [None; QUARANTINE_BLOCKS])
+        };
+        for (ptr, layout) in blocks.iter().flatten() {
+            unsafe { self.release(*ptr as *mut u8, *layout) };
+        }
+    }
+
+    // Hands a block that leaves the quarantine to the wrapped allocator. The poison must still be intact,
+    // otherwise something wrote to the block after it was freed.
+    unsafe fn release(&self, ptr: *mut u8, layout: Layout) {
+        if !slice::from_raw_parts(ptr, layout.size())
+            .iter()
+            .all(|&byte| byte == POISON)
+        {
+            report("write after free", ptr, layout);
+        }
+        self.inner
+            .dealloc(ptr.sub(front_size(&layout)), padded(&layout).unwrap());
+    }
+}
@@ -1,11 +1,8 @@
-use super::Locked;
+use super::page_list::{FreeBlock, PageHeader, Pages, PAGE_SIZE};
+use super::{AllocatorStats, Locked};
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::{mem, ptr, ptr::NonNull};
 
-struct ListNode {
-    next: Option<&'static mut ListNode>,
-}
-
 // The block sizes to use.
 // The sizes must each be power of 2 because they are also used as
 // the block alignment (alignments must be always powers of 2).
@@ -13,15 +10,52 @@ struct ListNode {
 // We don't define any block sizes smaller than 8 because each block
 // must be capable of storing a 64-bit pointer to the next block when freed.
 // For allocations greater than 2048 bytes we will fall back to a linked list allocator.
-const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+pub const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+// Blocks aren't taken from the fallback allocator one by one, but in 4KiB pages that are cut into blocks of one
+// size (see page_list), so a page whose blocks are all free again can be handed back to the fallback allocator.
+// The page header takes up the first block of a page at least, which would be a quarter or half of a page for
+// the largest block sizes, so blocks larger than this are taken from the fallback allocator one at a time.
+const LARGEST_PAGED_BLOCK: usize = 512;
+// Number of block sizes that are cut from pages, these are the first ones of BLOCK_SIZES
+const PAGED_CLASSES: usize = paged_classes();
+// Empty pages are kept for the next allocations up to this many per block size, so that allocating and freeing
+// a single block in a loop doesn't get and release a page every time. shrink releases them as well.
+const MAX_EMPTY_PAGES: usize = 1;
+
+const fn paged_classes() -> usize {
+    let mut count = 0;
+    while count < BLOCK_SIZES.len() && BLOCK_SIZES[count] <= LARGEST_PAGED_BLOCK {
+        count += 1;
+    }
+    count
+}
+
+fn page_layout() -> Layout {
+    Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).unwrap()
+}
+
+// The size and alignment of the blocks with the given index, for the ones taken from the fallback allocator
+fn block_layout(index: usize) -> Layout {
+    Layout::from_size_align(BLOCK_SIZES[index], BLOCK_SIZES[index]).unwrap()
+}
+
+// Offset of the first block in a page, the blocks that overlap the header can't be used
+fn first_block(index: usize) -> usize {
+    super::align_up(mem::size_of::<PageHeader>(), BLOCK_SIZES[index])
+}
+
+fn blocks_per_page(index: usize) -> usize {
+    (PAGE_SIZE - first_block(index)) / BLOCK_SIZES[index]
+}
 
 pub struct FixedSizeBlockAllocator {
-    // The list_heads field is an array of head pointers, one for each block size.
-    // This is implemented by using the len() of the BLOCK_SIZES slice as the array length.
-    // As a fallback allocator for allocations larger than the largest block size
-    // we use the allocator provided by the linked_list_allocator
-    list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
+    // The classes field is an array of page lists, one for each block size that is cut from pages.
+    // As a fallback allocator for the pages, the larger blocks and allocations larger than the largest block
+    // size we use the allocator provided by the linked_list_allocator
+    classes: [Pages; PAGED_CLASSES],
     fallback_allocator: linked_list_allocator::Heap,
+    stats: AllocatorStats,
 }
 
 impl FixedSizeBlockAllocator {
@@ -29,14 +63,14 @@ impl FixedSizeBlockAllocator {
     pub const fn new() -> Self {
         // The EMPTY constant is needed because to tell the Rust compiler that we
         // want to initialize the array with a constant value. Initializing the array
-        // directly as [None; BLOCK_SIZES.len()] does not work because then the compiler
-        // requires that Option<&'static mut ListNode> implements the Copy trait,
-        // which it does not. This is a current limitation of the Rust compiler,
-        // which might go away in the future.
-        const EMPTY: Option<&'static mut ListNode> = None;
+        // directly as [Pages::new(); PAGED_CLASSES] does not work because then the compiler
+        // requires that Pages implements the Copy trait, which it does not.
+        // This is a current limitation of the Rust compiler, which might go away in the future.
+        const EMPTY: Pages = Pages::new();
         FixedSizeBlockAllocator {
-            list_heads: [EMPTY; BLOCK_SIZES.len()],
+            classes: [EMPTY; PAGED_CLASSES],
             fallback_allocator: linked_list_allocator::Heap::empty(),
+            stats: AllocatorStats::new(),
         }
     }
 
@@ -48,8 +82,127 @@ impl FixedSizeBlockAllocator {
         self.fallback_allocator.init(heap_start, heap_size);
     }
 
+    // A copy of the allocation counters
+    pub fn stats(&self) -> AllocatorStats {
+        self.stats
+    }
+
+    // Number of bytes the heap currently spans
+    pub fn heap_size(&self) -> usize {
+        self.fallback_allocator.size()
+    }
+
+    // Number of bytes the block lists have taken from the fallback allocator in pages
+    pub fn page_bytes(&self) -> usize {
+        self.classes.iter().map(Pages::pages).sum::<usize>() * PAGE_SIZE
+    }
+
+    // Hands every page without an allocated block back to the fallback allocator and returns how many bytes
+    // were released. Allocations that don't fit into the fallback allocator call it before the heap is grown,
+    // but it can also be called whenever memory is tight.
+    pub fn shrink(&mut self) -> usize {
+        let mut released = 0;
+        for index in 0..PAGED_CLASSES {
+            while self.release_empty_page(index) {
+                released += PAGE_SIZE;
+            }
+        }
+        released
+    }
+
+    // Takes a block of the block size with the given index, from its pages if it is small enough
+    fn alloc_block(&mut self, index: usize) -> *mut u8 {
+        if index >= PAGED_CLASSES {
+            return self.fallback_alloc(block_layout(index));
+        }
+        if let Some(block) = self.classes[index].allocate() {
+            return block.as_ptr();
+        }
+        if !self.new_page(index) {
+            return ptr::null_mut();
+        }
+        self.classes[index]
+            .allocate()
+            .map_or(ptr::null_mut(), NonNull::as_ptr)
+    }
+
+    // Gives a block back. If its page ends up empty and there are enough empty pages already, the page is handed
+    // back to the fallback allocator.
+    // This function is unsafe because the caller must guarantee that `ptr` is a block of the block size with
+    // the given index that isn't used anymore.
+    unsafe fn dealloc_block(&mut self, ptr: *mut u8, index: usize) {
+        let ptr = NonNull::new(ptr).unwrap();
+        if index >= PAGED_CLASSES {
+            self.fallback_allocator.deallocate(ptr, block_layout(index));
+            return;
+        }
+
+        // verify the block has the size and alignment required for storing the free list
+        assert!(mem::size_of::<FreeBlock>() <= BLOCK_SIZES[index]);
+        assert!(mem::align_of::<FreeBlock>() <= BLOCK_SIZES[index]);
+
+        let class = &mut self.classes[index];
+        class.deallocate(ptr);
+        if class.empty_pages() > MAX_EMPTY_PAGES {
+            self.release_empty_page(index);
+        }
+    }
+
+    // Cuts a fresh page from the fallback allocator into blocks. Returns false if there is no memory left.
+    fn new_page(&mut self, index: usize) -> bool {
+        let page = self.fallback_alloc(page_layout());
+        if page.is_null() {
+            return false;
+        }
+        unsafe {
+            self.classes[index].add(
+                page as usize,
+                first_block(index),
+                BLOCK_SIZES[index],
+                blocks_per_page(index),
+            )
+        };
+        true
+    }
+
+    // Hands an empty page of the block size with the given index back to the fallback allocator.
+    // Returns false if there was none.
+    fn release_empty_page(&mut self, index: usize) -> bool {
+        let page = match self.classes[index].remove_empty() {
+            Some(page) => page,
+            None => return false,
+        };
+        unsafe {
+            self.fallback_allocator
+                .deallocate(NonNull::new_unchecked(page as *mut u8), page_layout());
+        }
+        true
+    }
+
     // Allocates using the fallback allocator
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
+        if let Ok(ptr) = self.fallback_allocator.allocate_first_fit(layout) {
+            return ptr.as_ptr();
+        }
+
+        // The empty pages of the block lists might make room for the allocation
+        if self.shrink() > 0 {
+            if let Ok(ptr) = self.fallback_allocator.allocate_first_fit(layout) {
+                return ptr.as_ptr();
+            }
+        }
+
+        // The heap is full, so map more memory behind it and try again. The free memory at the end of the heap
+        // might be aligned badly, so ask for enough to fit the allocation in any case.
+        let grown = super::grow_heap(
+            self.fallback_allocator.top(),
+            layout.size() + layout.align(),
+        );
+        if grown == 0 {
+            return ptr::null_mut();
+        }
+        unsafe { self.fallback_allocator.extend(grown) };
+
         match self.fallback_allocator.allocate_first_fit(layout) {
             // The NonNull type is an abstraction for a raw pointer that is guaranteed
             // to be not the null pointer. By mapping the Ok case to the NonNull::as_ptr
@@ -72,72 +225,55 @@ fn list_index(layout: &Layout) -> Option<usize> {
     // to find the index of the first block that is as least as large as the required_block_size.
     let required_block_size = layout.size().max(layout.align());
     // Note that we don't return the block size itself, but the index into the BLOCK_SIZES slice.
-    // The reason is that we want to use the returned index as an index into the list_heads array.
+    // The reason is that we want to use the returned index as an index into the classes array.
     BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
 }
 
+// The number of bytes an allocation with the given layout takes up: the whole block for the block lists,
+// and the requested size for the fallback allocator
+fn allocated_size(class: Option<usize>, layout: &Layout) -> usize {
+    match class {
+        Some(index) => BLOCK_SIZES[index],
+        None => layout.size(),
+    }
+}
+
 unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         // First, we use the Locked::lock method to get a mutable reference to the wrapped allocator instance.
         let mut allocator = self.lock();
 
         // Next, we call the list_index function we just defined to calculate the appropriate
-        // block size for the given layout and get the corresponding index into the list_heads array.
-        match list_index(&layout) {
-            // If the list index is Some, we try to remove the first node in the corresponding list
-            // started by list_heads[index] using the Option::take method.
-            Some(index) => {
-                match allocator.list_heads[index].take() {
-                    // If the list is not empty, we enter the Some(node) branch of the match statement,
-                    // where we point the head pointer of the list to the successor of the popped node
-                    // (by using take again).
-                    Some(node) => {
-                        allocator.list_heads[index] = node.next.take();
-                        // Finally, we return the popped node pointer as a *mut u8.
-                        node as *mut ListNode as *mut u8
-                    }
-                    // no block exists in list => allocate new block
-                    None => {
-                        // first get the current block size from the BLOCK_SIZES slice
-                        // and use it as both the size and the alignment for the new block.
-                        let block_size = BLOCK_SIZES[index];
-                        // only works if all block sizes are a power of two
-                        let block_align = block_size;
-                        // create a new Layout from it and call the fallback_alloc method to perform the allocation.
-                        // The reason for adjusting the layout and alignment is that the block will be added
-                        // to the block list on deallocation.
-                        let layout = Layout::from_size_align(block_size, block_align).unwrap();
-                        allocator.fallback_alloc(layout)
-                    }
-                }
-            }
+        // block size for the given layout and get the corresponding index into the classes array.
+        let class = list_index(&layout);
+        let ptr = match class {
+            // If the list index is Some, we take a block from the pages of that block size.
+            // New pages are allocated from the fallback allocator when none of them has a free block left.
+            // The largest blocks come straight from the fallback allocator.
+            Some(index) => allocator.alloc_block(index),
             // If this index is None, no block size fits for the allocation,
             // therefore we use the fallback_allocator using the fallback_alloc function.
             None => allocator.fallback_alloc(layout),
+        };
+
+        if !ptr.is_null() {
+            allocator
+                .stats
+                .record_alloc(class, allocated_size(class, &layout));
         }
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         let mut allocator = self.lock();
-        match list_index(&layout) {
-            // If list_index returns a block index, we need to add the freed memory block to the list.
-            Some(index) => {
-                // create a new ListNode that points to the current list head (by using Option::take again).
-                let new_node = ListNode {
-                    next: allocator.list_heads[index].take(),
-                };
-                // verify the block has the size and alignment required for storing node
-                assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
-                assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
-                // perform the write by converting the given *mut u8 pointer to a *mut ListNode pointer
-                // and then calling the unsafe write method on it.
-                let new_node_ptr = ptr as *mut ListNode;
-                new_node_ptr.write(new_node);
-                // The last step is to set the head pointer of the list, which is currently None since
-                // we called take on it, to our newly written ListNode. For that we convert
-                // the raw new_node_ptr to a mutable reference.
-                allocator.list_heads[index] = Some(&mut *new_node_ptr);
-            }
+        let class = list_index(&layout);
+        allocator
+            .stats
+            .record_dealloc(class, allocated_size(class, &layout));
+        match class {
+            // If list_index returns a block index, we need to add the freed memory block to the free blocks
+            // of its page, or give it back to the fallback allocator if it is one of the largest blocks.
+            Some(index) => allocator.dealloc_block(ptr, index),
             // If the index is None, no fitting block size exists in BLOCK_SIZES, which indicates
             // that the allocation was created by the fallback allocator.
             // Therefore we use its deallocate to free the memory again.
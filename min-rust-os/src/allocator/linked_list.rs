@@ -1,5 +1,5 @@
 use super::align_up;
-use super::Locked;
+use super::{AllocatorStats, Locked};
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::mem;
 use core::ptr;
@@ -29,8 +29,31 @@ impl ListNode {
     }
 }
 
+// How the free memory of a LinkedListAllocator is split up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fragmentation {
+    pub free_bytes: usize,
+    pub free_regions: usize,
+    pub largest_free_region: usize,
+}
+
+impl Fragmentation {
+    // The largest free region as a share of all free memory, in percent. 100 means that all free memory is in
+    // one piece, and the lower the value, the more a large allocation could fail despite enough free memory.
+    pub fn largest_region_percent(&self) -> usize {
+        if self.free_bytes == 0 {
+            100
+        } else {
+            self.largest_free_region * 100 / self.free_bytes
+        }
+    }
+}
+
 pub struct LinkedListAllocator {
     head: ListNode,
+    heap_start: usize,
+    heap_end: usize,
+    stats: AllocatorStats,
 }
 
 impl LinkedListAllocator {
@@ -43,6 +66,9 @@ impl LinkedListAllocator {
     pub const fn new() -> Self {
         Self {
             head: ListNode::new(0),
+            heap_start: 0,
+            heap_end: 0,
+            stats: AllocatorStats::new(),
         }
     }
 
@@ -51,30 +77,103 @@ impl LinkedListAllocator {
     // heap bounds are valid and that the heap is unused. This method must be
     // called only once.
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.heap_start = heap_start;
+        self.heap_end = heap_start + heap_size;
         self.add_free_region(heap_start, heap_size);
     }
 
-    // add the given memory region to the front of the list
+    // Number of bytes the heap currently spans
+    pub fn heap_size(&self) -> usize {
+        self.heap_end - self.heap_start
+    }
+
+    // A copy of the allocation counters
+    pub fn stats(&self) -> AllocatorStats {
+        self.stats
+    }
+
+    // add the given memory region to the list
     // The add_free_region method provides the fundamental push operation on the linked list.
-    // We currently only call this method from init, but it will also be the central method
-    // in our dealloc implementation. Remember, the dealloc method is called when an allocated
-    // memory region is freed again. To keep track of this freed memory region, we want to push
-    // it to the linked list.
+    // It is called from init, when the heap grows and in our dealloc implementation. Remember, the dealloc
+    // method is called when an allocated memory region is freed again. To keep track of this freed memory
+    // region, we want to push it to the linked list.
+    // The list is kept sorted by start address, so the neighbours of the region are right next to it in the
+    // list. If the region directly follows its predecessor or precedes its successor, they are merged into
+    // one region. Without merging, the heap would only ever be split into smaller regions, until large
+    // allocations fail even though there is plenty of free memory.
     unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
-        // The method takes a memory region represented by an address and size as argument and
-        // adds it to the front of the list. First, it ensures that the given region has the necessary size
-        // and alignment for storing a ListNode. Then it creates the node and inserts it to the list
+        // The method takes a memory region represented by an address and size as argument.
+        // First, it ensures that the given region has the necessary size and alignment for storing a ListNode.
+        // Then it looks for the position of the region in the list and inserts or merges it there.
 
         // ensure that the freed region is capable of holding ListNode
         assert_eq!(align_up(addr, mem::align_of::<ListNode>()), addr);
         assert!(size >= mem::size_of::<ListNode>());
 
-        // create a new list node and append it at the start of the list
+        // find the last node that starts before the region. This is the (dummy) head node
+        // if the region comes before all other free regions.
+        let mut current = &mut self.head;
+        while current
+            .next
+            .as_ref()
+            .map_or(false, |next| next.start_addr() < addr)
+        {
+            current = current.next.as_mut().unwrap();
+        }
+
+        // a region that overlaps a free region is freed twice (or was never allocated)
+        if let Some(next) = current.next.as_ref() {
+            assert!(
+                addr + size <= next.start_addr(),
+                "freed region {:#x} overlaps a free region",
+                addr
+            );
+        }
+        // the dummy head node has a size of 0 and is never merged
+        assert!(
+            current.size == 0 || current.end_addr() <= addr,
+            "freed region {:#x} overlaps a free region",
+            addr
+        );
+
+        // create a new list node for the region and merge the successor into it if they are adjacent
         let mut node = ListNode::new(size);
-        node.next = self.head.next.take();
-        let node_ptr = addr as *mut ListNode;
-        node_ptr.write(node);
-        self.head.next = Some(&mut *node_ptr)
+        node.next = current.next.take();
+        if let Some(next) = node.next.take() {
+            if addr + size == next.start_addr() {
+                node.size += next.size;
+                node.next = next.next.take();
+            } else {
+                node.next = Some(next);
+            }
+        }
+
+        if current.size > 0 && current.end_addr() == addr {
+            // the region directly follows its predecessor, so the predecessor simply grows
+            current.size += node.size;
+            current.next = node.next;
+        } else {
+            let node_ptr = addr as *mut ListNode;
+            node_ptr.write(node);
+            current.next = Some(&mut *node_ptr)
+        }
+    }
+
+    // Returns how fragmented the free memory is. This walks the whole free list.
+    pub fn fragmentation(&self) -> Fragmentation {
+        let mut fragmentation = Fragmentation {
+            free_bytes: 0,
+            free_regions: 0,
+            largest_free_region: 0,
+        };
+        let mut current = self.head.next.as_ref();
+        while let Some(region) = current {
+            fragmentation.free_bytes += region.size;
+            fragmentation.free_regions += 1;
+            fragmentation.largest_free_region = fragmentation.largest_free_region.max(region.size);
+            current = region.next.as_ref();
+        }
+        fragmentation
     }
 
     // Looks for a free region with the given size and alignment and removes it from the list.
@@ -120,8 +219,14 @@ impl LinkedListAllocator {
         // usable after the allocation. This part of the region must store its own ListNode after the allocation,
         // so it must be large enough to do so. The check verifies exactly that: either the allocation
         // fits perfectly (excess_size == 0) or the excess size is large enough to store a ListNode.
+        // The same goes for the gap in front of the allocation that aligning the start address leaves.
 
         let alloc_start = align_up(region.start_addr(), align);
+        let gap_size = alloc_start - region.start_addr();
+        if gap_size > 0 && gap_size < mem::size_of::<ListNode>() {
+            // gap too small to hold a ListNode, so it would be lost
+            return Err(());
+        }
         let alloc_end = alloc_start.checked_add(size).ok_or(())?;
 
         if alloc_end > region.end_addr() {
@@ -129,6 +234,13 @@ impl LinkedListAllocator {
             return Err(());
         }
 
+        let excess_size = region.end_addr() - alloc_end;
+        if excess_size > 0 && excess_size < mem::size_of::<ListNode>() {
+            // rest of region too small to hold a ListNode (required because the
+            // allocation splits the region in a used and a free part)
+            return Err(());
+        }
+
         // region suitable for allocation
         Ok(alloc_start)
     }
@@ -158,19 +270,40 @@ unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
         // Using alloc_start, the allocation size, and the end address of the region,
         // it calculates the end address of the allocation and the excess size again.
         // If the excess size is not null, it calls add_free_region to add the excess size of
-        // the memory region back to the free list. Finally, it returns the alloc_start
+        // the memory region back to the free list. The gap that aligning alloc_start left in front of
+        // the allocation goes back to the free list as well. Finally, it returns the alloc_start
         // address casted as a *mut u8 pointer.
 
         // perform layout adjustments to ensure that each allocated block is capable of storing a ListNode
         let (size, align) = LinkedListAllocator::size_align(layout);
         let mut allocator = self.lock();
 
-        if let Some((region, alloc_start)) = allocator.find_region(size, align) {
+        let mut found = allocator.find_region(size, align);
+        if found.is_none() {
+            // No free region is large enough, so map more memory behind the heap and try again.
+            // The new region starts page aligned, which might not be enough for the allocation.
+            let heap_end = allocator.heap_end;
+            let grown = super::grow_heap(heap_end, size + align);
+            if grown > 0 {
+                allocator.add_free_region(heap_end, grown);
+                allocator.heap_end += grown;
+                found = allocator.find_region(size, align);
+            }
+        }
+
+        if let Some((region, alloc_start)) = found {
+            // The node lives at the start of the region, which the gap might reuse
+            let (region_start, region_end) = (region.start_addr(), region.end_addr());
             let alloc_end = alloc_start.checked_add(size).expect("overflow");
-            let excess_size = region.end_addr() - alloc_end;
+            let excess_size = region_end - alloc_end;
             if excess_size > 0 {
                 allocator.add_free_region(alloc_end, excess_size);
             }
+            let gap_size = alloc_start - region_start;
+            if gap_size > 0 {
+                allocator.add_free_region(region_start, gap_size);
+            }
+            allocator.stats.record_alloc(None, size);
             alloc_start as *mut u8
         } else {
             ptr::null_mut()
@@ -185,12 +318,11 @@ unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
         // perform layout adjustments to ensure that each allocated block is capable of storing a ListNode
         let (size, _) = LinkedListAllocator::size_align(layout);
 
-        self.lock().add_free_region(ptr as usize, size)
-
-        // The main problem of our implementation is that it only splits the heap into smaller blocks, but never merges them back together.
-        // Instead of inserting freed memory blocks at the beginning of the linked list on deallocate,
-        // it should always keep the list sorted by start address. This way, merging can be performed directly on
-        // the deallocate call by examining the addresses and sizes of the two neighbour blocks in the list.
-        // Of course, the deallocation operation is slower this way, but it prevents the heap fragmentation
+        let mut allocator = self.lock();
+        allocator.stats.record_dealloc(None, size);
+        // add_free_region merges the region with its neighbours, which prevents the heap fragmentation.
+        // Keeping the list sorted makes deallocation slower though, because it has to find the
+        // position of the region in the list first.
+        allocator.add_free_region(ptr as usize, size)
     }
 }
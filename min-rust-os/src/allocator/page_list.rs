@@ -0,0 +1,176 @@
+use core::ptr::NonNull;
+
+// FixedSizeBlockAllocator and the slab caches both cut 4KiB pages into blocks of one size. Every page starts with
+// a header that keeps the free blocks of the page and links the page into one of the lists of Pages, so that a
+// page whose blocks are all free again can be given back. Pages are aligned to their size, so the page of a block
+// is found by aligning the block's address down.
+pub const PAGE_SIZE: usize = 4096;
+
+// The header at the start of every page. The blocks follow it.
+pub struct PageHeader {
+    prev: Option<NonNull<PageHeader>>,
+    next: Option<NonNull<PageHeader>>,
+    // The free blocks of the page form a linked list through the blocks themselves
+    free: Option<NonNull<FreeBlock>>,
+    in_use: usize,
+    blocks: usize,
+}
+
+// A free block stores the next free block of its page, so blocks can't be smaller than this
+pub struct FreeBlock {
+    next: Option<NonNull<FreeBlock>>,
+}
+
+// A doubly linked list of pages, so that a page can move to another list in constant time
+struct PageList {
+    head: Option<NonNull<PageHeader>>,
+    len: usize,
+}
+
+impl PageList {
+    const fn new() -> Self {
+        PageList { head: None, len: 0 }
+    }
+
+    unsafe fn push(&mut self, mut page: NonNull<PageHeader>) {
+        let header = page.as_mut();
+        header.prev = None;
+        header.next = self.head;
+        if let Some(mut head) = self.head {
+            head.as_mut().prev = Some(page);
+        }
+        self.head = Some(page);
+        self.len += 1;
+    }
+
+    unsafe fn remove(&mut self, mut page: NonNull<PageHeader>) {
+        let header = page.as_mut();
+        match header.prev {
+            Some(mut prev) => prev.as_mut().next = header.next,
+            None => self.head = header.next,
+        }
+        if let Some(mut next) = header.next {
+            next.as_mut().prev = header.prev;
+        }
+        self.len -= 1;
+    }
+}
+
+// The pages of one block size. Full pages have no free block left, partial pages have some, and empty pages have
+// no block in use. Blocks come from partial pages first, so that empty pages stay empty and can be given back.
+pub struct Pages {
+    full: PageList,
+    partial: PageList,
+    empty: PageList,
+}
+
+// The pages are owned by whoever owns the Pages, so they can be moved to another thread together. The lint can't
+// tell pointers to owned memory from shared ones.
+#[allow(clippy::non_send_fields_in_send_ty)]
+unsafe impl Send for Pages {}
+
+impl Pages {
+    pub const fn new() -> Self {
+        Pages {
+            full: PageList::new(),
+            partial: PageList::new(),
+            empty: PageList::new(),
+        }
+    }
+
+    pub fn full_pages(&self) -> usize {
+        self.full.len
+    }
+
+    pub fn partial_pages(&self) -> usize {
+        self.partial.len
+    }
+
+    pub fn empty_pages(&self) -> usize {
+        self.empty.len
+    }
+
+    pub fn pages(&self) -> usize {
+        self.full.len + self.partial.len + self.empty.len
+    }
+
+    // Cuts the page at `addr` into `blocks` blocks of `block_size` bytes, the first of which starts at offset
+    // `first_block`, and adds it to the empty pages.
+    // This function is unsafe because the caller must guarantee that the page is PAGE_SIZE aligned, unused and
+    // large enough for the blocks, and that the blocks leave room for the header and can hold a FreeBlock.
+    pub unsafe fn add(
+        &mut self,
+        addr: usize,
+        first_block: usize,
+        block_size: usize,
+        blocks: usize,
+    ) {
+        // Chain the blocks together, so that the lowest address is allocated first
+        let mut free = None;
+        for block in (0..blocks).rev() {
+            let node = (addr + first_block + block * block_size) as *mut FreeBlock;
+            node.write(FreeBlock { next: free });
+            free = NonNull::new(node);
+        }
+
+        let page = addr as *mut PageHeader;
+        page.write(PageHeader {
+            prev: None,
+            next: None,
+            free,
+            in_use: 0,
+            blocks,
+        });
+        self.empty.push(NonNull::new_unchecked(page));
+    }
+
+    // Takes a free block, or returns `None` if every page is full
+    pub fn allocate(&mut self) -> Option<NonNull<u8>> {
+        let page = self.partial.head.or(self.empty.head)?;
+        unsafe {
+            self.list_for(page).remove(page);
+            let header = &mut *page.as_ptr();
+            let block = header
+                .free
+                .expect("a partial or empty page has no free block");
+            header.free = block.as_ref().next;
+            header.in_use += 1;
+            self.list_for(page).push(page);
+            Some(block.cast())
+        }
+    }
+
+    // Gives a block back to its page.
+    // This function is unsafe because the caller must guarantee that `block` was returned by allocate and is not
+    // used anymore.
+    pub unsafe fn deallocate(&mut self, block: NonNull<u8>) {
+        let page_addr = block.as_ptr() as usize & !(PAGE_SIZE - 1);
+        let page = NonNull::new_unchecked(page_addr as *mut PageHeader);
+
+        self.list_for(page).remove(page);
+        let header = &mut *page.as_ptr();
+        let node: NonNull<FreeBlock> = block.cast();
+        node.as_ptr().write(FreeBlock { next: header.free });
+        header.free = Some(node);
+        header.in_use -= 1;
+        self.list_for(page).push(page);
+    }
+
+    // Takes an empty page off the lists and returns its address, so that the caller can give it back to where
+    // it came from. Returns `None` if there is no empty page.
+    pub fn remove_empty(&mut self) -> Option<usize> {
+        let page = self.empty.head?;
+        unsafe { self.empty.remove(page) };
+        Some(page.as_ptr() as usize)
+    }
+
+    // The list the page belongs on, going by how many of its blocks are in use
+    fn list_for(&mut self, page: NonNull<PageHeader>) -> &mut PageList {
+        let header = unsafe { page.as_ref() };
+        match header.in_use {
+            0 => &mut self.empty,
+            in_use if in_use == header.blocks => &mut self.full,
+            _ => &mut self.partial,
+        }
+    }
+}
@@ -0,0 +1,185 @@
+use super::page_list::{FreeBlock, PageHeader, Pages, PAGE_SIZE};
+use crate::memory::{physical_memory_offset, GlobalFrameAllocator};
+use core::marker::PhantomData;
+use core::mem;
+use core::ptr::{self, NonNull};
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame},
+    PhysAddr,
+};
+
+// Empty slabs are kept around for the next allocations up to this many. Any more go back to the frame allocator,
+// so that a burst of allocations doesn't tie up memory forever.
+const MAX_EMPTY_SLABS: usize = 1;
+
+// How many slabs a cache has on each list and how many objects are allocated from them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub full_slabs: usize,
+    pub partial_slabs: usize,
+    pub empty_slabs: usize,
+    pub objects_in_use: usize,
+}
+
+// A cache of objects of type T. Unlike the block lists of FixedSizeBlockAllocator, which round every allocation
+// up to the next power of two, the cache cuts its slabs into objects of exactly size_of::<T>() bytes (objects
+// smaller than a pointer take up a pointer, because free objects store the next free object).
+// Every slab is one 4KiB frame, which the cache accesses through the physical memory mapping, so slabs don't
+// need any virtual memory of their own. Slabs are kept on three lists (see page_list): full slabs have no free
+// object left, partial slabs have some, and empty slabs have no object in use. Allocations come from partial
+// slabs first, so that empty slabs stay empty and can be given back to the frame allocator.
+pub struct Cache<T> {
+    slabs: Pages,
+    objects_in_use: usize,
+    _marker: PhantomData<T>,
+}
+
+impl<T> Cache<T> {
+    // Free objects must be able to hold a FreeBlock
+    const ALIGN: usize = max(mem::align_of::<T>(), mem::align_of::<FreeBlock>());
+    const OBJECT_SIZE: usize = align_up(
+        max(mem::size_of::<T>(), mem::size_of::<FreeBlock>()),
+        Self::ALIGN,
+    );
+    // Offset of the first object in a slab
+    const FIRST_OBJECT: usize = align_up(mem::size_of::<PageHeader>(), Self::ALIGN);
+    pub const OBJECTS_PER_SLAB: usize = if Self::FIRST_OBJECT < PAGE_SIZE {
+        (PAGE_SIZE - Self::FIRST_OBJECT) / Self::OBJECT_SIZE
+    } else {
+        0
+    };
+
+    // Creates an empty cache. No memory is allocated until the first object is.
+    pub const fn new() -> Self {
+        Cache {
+            slabs: Pages::new(),
+            objects_in_use: 0,
+            _marker: PhantomData,
+        }
+    }
+
+    // Returns uninitialised memory for one object, or `None` if no frame is left for a new slab.
+    // Panics if T doesn't fit into a slab together with the slab header.
+    pub fn allocate(&mut self) -> Option<NonNull<T>> {
+        assert!(
+            Self::OBJECTS_PER_SLAB > 0,
+            "objects of {} bytes don't fit into a slab",
+            mem::size_of::<T>()
+        );
+
+        let object = match self.slabs.allocate() {
+            Some(object) => object,
+            None => {
+                self.grow()?;
+                self.slabs.allocate()?
+            }
+        };
+        self.objects_in_use += 1;
+        Some(object.cast())
+    }
+
+    // Gives an object back to its slab without dropping it. If the slab ends up empty and there are enough
+    // empty slabs already, the slab's frame is given back to the frame allocator.
+    // This function is unsafe because the caller must guarantee that `ptr` was returned by allocate on this
+    // cache and is not used anymore.
+    pub unsafe fn deallocate(&mut self, ptr: NonNull<T>) {
+        let slab_addr = ptr.as_ptr() as usize & !(PAGE_SIZE - 1);
+        let offset = ptr.as_ptr() as usize - slab_addr;
+        assert!(
+            offset >= Self::FIRST_OBJECT && (offset - Self::FIRST_OBJECT) % Self::OBJECT_SIZE == 0,
+            "{:p} is not an object of a slab",
+            ptr
+        );
+
+        self.slabs.deallocate(ptr.cast());
+        self.objects_in_use -= 1;
+
+        if self.slabs.empty_pages() > MAX_EMPTY_SLABS {
+            self.release_empty_slab();
+        }
+    }
+
+    // Moves `value` into a new object. Returns `None` if no frame is left for a new slab.
+    pub fn alloc(&mut self, value: T) -> Option<NonNull<T>> {
+        let ptr = self.allocate()?;
+        unsafe { ptr.as_ptr().write(value) };
+        Some(ptr)
+    }
+
+    // Drops the object and gives its memory back to the cache.
+    // This function is unsafe for the same reason as deallocate.
+    pub unsafe fn free(&mut self, ptr: NonNull<T>) {
+        ptr::drop_in_place(ptr.as_ptr());
+        self.deallocate(ptr);
+    }
+
+    // Gives every empty slab back to the frame allocator and returns how many there were
+    pub fn shrink(&mut self) -> usize {
+        let count = self.slabs.empty_pages();
+        while self.release_empty_slab() {}
+        count
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            full_slabs: self.slabs.full_pages(),
+            partial_slabs: self.slabs.partial_pages(),
+            empty_slabs: self.slabs.empty_pages(),
+            objects_in_use: self.objects_in_use,
+        }
+    }
+
+    // Turns a fresh frame into an empty slab. Returns `None` if there is no frame left.
+    fn grow(&mut self) -> Option<()> {
+        let frame: PhysFrame = GlobalFrameAllocator.allocate_frame()?;
+        let slab_addr =
+            (physical_memory_offset() + frame.start_address().as_u64()).as_u64() as usize;
+        unsafe {
+            self.slabs.add(
+                slab_addr,
+                Self::FIRST_OBJECT,
+                Self::OBJECT_SIZE,
+                Self::OBJECTS_PER_SLAB,
+            )
+        };
+        Some(())
+    }
+
+    // Gives an empty slab back to the frame allocator. Returns false if there was none.
+    fn release_empty_slab(&mut self) -> bool {
+        let slab_addr = match self.slabs.remove_empty() {
+            Some(slab_addr) => slab_addr,
+            None => return false,
+        };
+        let phys = slab_addr as u64 - physical_memory_offset().as_u64();
+        unsafe {
+            GlobalFrameAllocator
+                .deallocate_frame(PhysFrame::containing_address(PhysAddr::new(phys)))
+        };
+        true
+    }
+}
+
+impl<T> Drop for Cache<T> {
+    // The objects live in the slabs, so every object must have been freed before the cache goes away
+    fn drop(&mut self) {
+        assert_eq!(
+            self.objects_in_use, 0,
+            "dropped a slab cache with objects in use"
+        );
+        self.shrink();
+    }
+}
+
+const fn max(a: usize, b: usize) -> usize {
+    if a > b {
+        a
+    } else {
+        b
+    }
+}
+
+// Like super::align_up, but usable in constants
+const fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
@@ -0,0 +1,225 @@
+use crate::serial_println;
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::arch::asm;
+use core::ops::Deref;
+use spin::Mutex;
+
+// Number of live allocations that can be recorded. The table can't live on the heap it keeps track of, so it has
+// a fixed size. Allocations beyond that are only counted.
+const MAX_RECORDS: usize = 1024;
+// Number of return addresses recorded for every allocation. The innermost ones belong to the allocation functions
+// of the alloc crate, the call site that allocated follows after them.
+pub const CALLER_FRAMES: usize = 4;
+// A frame pointer that is this far above the previous one doesn't belong to the same stack anymore
+const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+// A live allocation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Record {
+    pub addr: usize,
+    pub size: usize,
+    // The time stamp counter when the allocation was made
+    pub timestamp: u64,
+    // Return addresses of the innermost stack frames at the time of the allocation, 0 where the stack ended
+    pub callers: [usize; CALLER_FRAMES],
+}
+
+impl Record {
+    const EMPTY: Record = Record {
+        addr: 0,
+        size: 0,
+        timestamp: 0,
+        callers: [0; CALLER_FRAMES],
+    };
+}
+
+// An open addressing hash table of the live allocations, keyed by address. Slots with an address of 0 are free.
+struct Tracker {
+    records: [Record; MAX_RECORDS],
+    live: usize,
+    // Allocations that couldn't be recorded because the table was full
+    untracked: usize,
+}
+
+// There is a single table for every TrackingAllocator, which is fine since only the global allocator is tracked
+static TRACKER: Mutex<Tracker> = Mutex::new(Tracker {
+    records: [Record::EMPTY; MAX_RECORDS],
+    live: 0,
+    untracked: 0,
+});
+
+impl Tracker {
+    fn slot(addr: usize) -> usize {
+        // Allocations are at least 8 byte aligned most of the time, so the low bits carry no information
+        (addr >> 3) % MAX_RECORDS
+    }
+
+    fn insert(&mut self, record: Record) {
+        if self.live == MAX_RECORDS {
+            self.untracked += 1;
+            return;
+        }
+        let mut index = Self::slot(record.addr);
+        while self.records[index].addr != 0 {
+            index = (index + 1) % MAX_RECORDS;
+        }
+        self.records[index] = record;
+        self.live += 1;
+    }
+
+    fn remove(&mut self, addr: usize) {
+        let start = Self::slot(addr);
+        let found = (0..MAX_RECORDS)
+            .map(|probe| (start + probe) % MAX_RECORDS)
+            .take_while(|&index| self.records[index].addr != 0)
+            .find(|&index| self.records[index].addr == addr);
+        let mut index = match found {
+            Some(index) => index,
+            // The allocation was made while the table was full
+            None => {
+                self.untracked = self.untracked.saturating_sub(1);
+                return;
+            }
+        };
+        self.records[index] = Record::EMPTY;
+        self.live -= 1;
+
+        // Close the gap, so that later lookups don't stop at it: move every following record that would have
+        // been found at or before the free slot into it
+        let mut next = index;
+        loop {
+            next = (next + 1) % MAX_RECORDS;
+            let record = self.records[next];
+            if record.addr == 0 {
+                break;
+            }
+            let home = Self::slot(record.addr);
+            let distance_to_gap = (index + MAX_RECORDS - home) % MAX_RECORDS;
+            let distance_to_next = (next + MAX_RECORDS - home) % MAX_RECORDS;
+            if distance_to_gap < distance_to_next {
+                self.records[index] = record;
+                self.records[next] = Record::EMPTY;
+                index = next;
+            }
+        }
+    }
+
+    fn records(&self) -> impl Iterator<Item = &Record> {
+        self.records.iter().filter(|record| record.addr != 0)
+    }
+}
+
+// Wraps an allocator to record every live allocation with its size, the time it was made and the return
+// addresses of the code that made it. dump lists the allocations that are still outstanding, and
+// allocated_since finds the ones a piece of code left behind. The call sites can be resolved with addr2line
+// or objdump on the kernel binary. Walking the stack relies on frame pointers, so builds with heap-track need
+// `-C force-frame-pointers=yes` in RUSTFLAGS, like the test-heap-track alias in .cargo/config.toml passes.
+pub struct TrackingAllocator<A> {
+    inner: A,
+}
+
+impl<A> TrackingAllocator<A> {
+    pub const fn new(inner: A) -> Self {
+        TrackingAllocator { inner }
+    }
+}
+
+// The wrapped allocator stays accessible, e.g. to initialise it
+impl<A> Deref for TrackingAllocator<A> {
+    type Target = A;
+
+    fn deref(&self) -> &A {
+        &self.inner
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for TrackingAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc(layout);
+        if !ptr.is_null() {
+            let record = Record {
+                addr: ptr as usize,
+                size: layout.size(),
+                timestamp: timestamp(),
+                callers: callers(),
+            };
+            TRACKER.lock().insert(record);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        TRACKER.lock().remove(ptr as usize);
+        self.inner.dealloc(ptr, layout);
+    }
+}
+
+// The current value of the time stamp counter. Take one before running some code and pass it to allocated_since
+// afterwards to find what the code leaked.
+pub fn timestamp() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+// Returns the number of live allocations and their total size in bytes
+pub fn live() -> (usize, usize) {
+    let tracker = TRACKER.lock();
+    let bytes = tracker.records().map(|record| record.size).sum();
+    (tracker.live + tracker.untracked, bytes)
+}
+
+// Returns the number of live allocations that were made at or after `timestamp`
+pub fn allocated_since(timestamp: u64) -> usize {
+    TRACKER
+        .lock()
+        .records()
+        .filter(|record| record.timestamp >= timestamp)
+        .count()
+}
+
+// Calls `f` for every recorded live allocation. The table is locked in the meantime, so `f` must not allocate.
+pub fn for_each(f: impl FnMut(&Record)) {
+    TRACKER.lock().records().for_each(f);
+}
+
+// Lists every live allocation on serial. Sorting them would need the heap, so they come in table order.
+pub fn dump() {
+    let tracker = TRACKER.lock();
+    serial_println!(
+        "{} live allocations ({} not recorded):",
+        tracker.live + tracker.untracked,
+        tracker.untracked
+    );
+    for record in tracker.records() {
+        serial_println!(
+            "  {:#x}: {} bytes at tsc {}, called from {:#x?}",
+            record.addr,
+            record.size,
+            record.timestamp,
+            record.callers
+        );
+    }
+}
+
+// Walks the frame pointer chain and returns the innermost return addresses. Every frame starts with the
+// frame pointer of its caller, followed by the return address into the caller.
+#[inline(always)]
+fn callers() -> [usize; CALLER_FRAMES] {
+    let mut callers = [0; CALLER_FRAMES];
+    let mut frame: *const usize;
+    unsafe { asm!("mov {}, rbp", out(reg) frame) };
+
+    for caller in callers.iter_mut() {
+        if frame.is_null() || frame as usize % 8 != 0 {
+            break;
+        }
+        let (next, return_address) = unsafe { (*frame as *const usize, *frame.add(1)) };
+        *caller = return_address;
+        // The stack grows down, so the frames of the callers lie at higher addresses. Anything else means that
+        // the chain ended.
+        if next <= frame || next as usize - frame as usize > MAX_FRAME_SIZE {
+            break;
+        }
+        frame = next;
+    }
+    callers
+}
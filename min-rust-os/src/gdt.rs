@@ -1,3 +1,5 @@
+use crate::memory;
+use core::ptr::addr_of;
 use lazy_static::lazy_static;
 #[allow(deprecated)]
 use x86_64::instructions::segmentation::set_cs;
@@ -7,31 +9,31 @@ use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+const DOUBLE_FAULT_STACK_PAGES: u64 = 5;
 
-// lazy_static is used because Rust's const evaluator does not yet do this initialization at compile time
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            // We haven't implemented memory management yet, so we don't have a proper way to allocate a new stack.
-            // Instead, we use a static mut array as stack storage for now. The unsafe is required because the compiler
-            // can't guarantee race freedom when mutable statics are accessed.
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-        tss
-    };
-}
+// The TSS is a mutable static instead of a lazy_static, because its interrupt stacks are replaced once memory
+// management is up (see init_ist_stacks). The CPU reads the interrupt stack table from memory every time it
+// switches stacks, so updating an entry takes effect right away.
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
+// Stack used for double faults until init_ist_stacks runs. Double faults can happen before there is a heap
+// or a frame allocator, so we use a static mut array as stack storage for now. It has no guard page,
+// so an overflow of this stack silently corrupts the memory below it.
+const BOOT_STACK_SIZE: usize = 4096 * 5;
+static mut BOOT_STACK: [u8; BOOT_STACK_SIZE] = [0; BOOT_STACK_SIZE];
 
+// lazy_static is used because Rust's const evaluator does not yet do this initialization at compile time
 lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        // The unsafe is required because the compiler can't guarantee race freedom when mutable statics are accessed.
+        // The descriptor only stores the address of the TSS, it doesn't hold on to the reference.
+        let tss = unsafe {
+            let tss = addr_of!(TSS);
+            &*tss
+        };
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
         (
             gdt,
             Selectors {
@@ -48,6 +50,11 @@ struct Selectors {
 }
 
 pub fn init() {
+    unsafe {
+        let stack_start = VirtAddr::from_ptr(addr_of!(BOOT_STACK));
+        TSS.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = stack_start + BOOT_STACK_SIZE;
+    }
+
     GDT.0.load();
     // The reason for the unsafe block is that it might be possible to break memory safety by loading invalid selectors.
     unsafe {
@@ -57,3 +64,11 @@ pub fn init() {
         load_tss(GDT.1.tss_selector);
     }
 }
+
+// Replaces the boot stack in the interrupt stack table with a stack from memory::alloc_kernel_stack,
+// which has a guard page below it. Must be called after the kernel mapper and frame allocator are set up.
+pub fn init_ist_stacks() {
+    let stack_top = memory::alloc_kernel_stack(DOUBLE_FAULT_STACK_PAGES)
+        .expect("failed to allocate the double fault stack");
+    unsafe { TSS.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = stack_top };
+}
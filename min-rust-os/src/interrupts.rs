@@ -1,5 +1,6 @@
 use crate::gdt;
 use crate::hlt_loop;
+use crate::memory;
 use crate::print;
 use crate::println;
 use lazy_static::lazy_static;
@@ -125,10 +126,17 @@ extern "x86-interrupt" fn page_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
-    println!("EXCEPTION: PAGE FAULT");
     // The CR2 register is automatically set by the CPU on a page fault and contains
     // the accessed virtual address that caused the page fault.
-    println!("Accessed Address: {:?}", Cr2::read());
+    let addr = Cr2::read();
+    // Some faults are expected, for example the first access to a page of a region that is backed on demand.
+    // Once the memory module has mapped the page, returning from the handler retries the faulting instruction.
+    if memory::handle_page_fault(addr, error_code) {
+        return;
+    }
+
+    println!("EXCEPTION: PAGE FAULT");
+    println!("Accessed Address: {:?}", addr);
     println!("Error Code: {:?}", error_code);
     println!("{:#?}", stack_frame);
     // Can't continue execution without resolving the page fault, so we enter a hlt_loop at the end.
@@ -8,6 +8,7 @@
 #![feature(abi_x86_interrupt)]
 #![feature(alloc_error_handler)]
 #![feature(const_mut_refs)]
+#![feature(asm)]
 
 extern crate alloc;
 
@@ -18,8 +19,10 @@ pub mod allocator;
 pub mod gdt;
 pub mod interrupts;
 pub mod memory;
+pub mod random;
 pub mod serial;
 pub mod task;
+pub mod test_support;
 pub mod vga_buffer;
 
 pub trait Testable {
@@ -82,6 +85,9 @@ pub fn exit_qemu(exit_code: QemuExitCode) {
 
 pub fn init() {
     gdt::init();
+    // Turn on NX before anything maps pages with the NO_EXECUTE flag
+    memory::enable_nx();
+    memory::init_pat();
     interrupts::init_idt();
     unsafe { interrupts::PICS.lock().initialize() };
     // The interrupts::enable function of the x86_64 crate executes the special sti instruction
@@ -18,18 +18,19 @@ use alloc::{boxed::Box, rc::Rc, vec, vec::Vec};
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 use min_rust_os::allocator;
+use min_rust_os::gdt;
 use min_rust_os::memory;
-use min_rust_os::memory::BootInfoFrameAllocator;
+use min_rust_os::memory::swap::RamDisk;
+use min_rust_os::memory::{CacheMode, GlobalFrameAllocator};
 use min_rust_os::task::executor::Executor;
 use min_rust_os::task::{keyboard, Task};
+use min_rust_os::{println, vga_buffer};
 // use min_rust_os::task::{simple_executor::SimpleExecutor};
 // use min_rust_os::memory::{active_level_4_table, translate_addr};
 // use x86_64::structures::paging::Page;
-use x86_64::VirtAddr;
+use x86_64::{PhysAddr, VirtAddr};
 // use x86_64::structures::paging::{Translate, PageTable};
 
-mod vga_buffer;
-
 // Since the standard library has been removed, Some functionality needs to be implemented manually.
 // Like a panic_handler and stack unwinding which is used to to run the destructors of all live
 // stack variables in case of a panic.
@@ -102,38 +103,7 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     // So the only way to access the table is through some virtual page that is mapped to the physical frame at address 0x1000.
     // This problem of creating mappings for page table frames is a general problem, since the kernel
     // needs to access the page tables regularly, for example when allocating a stack for a new thread.
-    //    use x86_64::registers::control::Cr3;
-    //    let (level_4_page_table, _) = Cr3::read();
-    //    println!(
-    //        "Level 4 page table at: {:?}",
-    //        level_4_page_table.start_address()
-    //    );
-    //
-    //    // Use memory module to traverse the entries of the level 4 table manually:
-    //    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    //    let l4_table = unsafe { active_level_4_table(phys_mem_offset) };
-    //
-    //    for (i, entry) in l4_table.iter().enumerate() {
-    //        if !entry.is_unused() {
-    //            println!("L4 entry {}: {:?}", i, entry);
-    //
-    //            // To traverse the page tables further and take a look at a level 3 table,
-    //            // we can take the mapped frame of an entry convert it to a virtual address again:
-    //
-    //            // Get physical address and convert it
-    //            let phys = entry.frame().unwrap().start_address();
-    //            let virt = phys.as_u64() + boot_info.physical_memory_offset;
-    //            let ptr = VirtAddr::new(virt).as_mut_ptr();
-    //            let l3_table: &PageTable = unsafe { &*ptr };
-    //
-    //            // print non-empty entries of level 3 table
-    //            for (i, entry) in l3_table.iter().enumerate() {
-    //                if !entry.is_unused() {
-    //                    println!("L3 entry {}: {:?}", i, entry);
-    //                }
-    //            }
-    //        }
-    //    }
+    // memory::dump_mappings walks the page tables this way and prints every mapped range, see below.
 
     // Use translation function to manually translate addresses
     //    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
@@ -157,10 +127,18 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
     // Create a new mapping for a previously unmapped page. THIS IS EXPERIMENTAL AND UNSAFE
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    // The kernel mapper lives in a static, so that the page fault handler can map pages on demand
+    unsafe { memory::init_kernel_mapper(phys_mem_offset) };
+    // Print the kernel's address space to serial (or to VGA with `&mut *vga_buffer::WRITER.lock()`)
+    // memory::dump_mappings(&mut *min_rust_os::serial::SERIAL1.lock()).unwrap();
     // The memory map can be queried from the BIOS or UEFI firmware, but only very early in the boot process.
     // For this reason, it must be provided by the bootloader because there is no way for the kernel to retrieve it later.
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) }; // memory::EmptyFrameAllocator;
+    // The bitmap frame allocator is built once from the memory map and is able to take frames back.
+    // It lives in a static so that page tables of new address spaces can be allocated and freed later on.
+    unsafe { memory::init_frame_allocator(&boot_info.memory_map, phys_mem_offset) };
+    let mut frame_allocator = GlobalFrameAllocator;
+    // Report how the physical memory is used
+    // println!("{}", memory::MemoryStats::current().unwrap());
 
     // Manually map unused page using address 0. Normally, this page should stay unused to guarantee
     // that dereferencing a null pointer causes a page fault, so we know that the bootloader leaves it unmapped.
@@ -170,8 +148,46 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     //    let page_ptr: *mut u64 = page.start_address().as_mut_ptr();
     //    unsafe { page_ptr.offset(400).write_volatile(0x_f021_f077_f065_f04e) };
 
+    // Place the heap, the kernel stacks and the MMIO windows at random addresses
+    memory::vma::KERNEL_VMAS.lock().enable_randomization();
+
     // Initialise the heap memory region
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialisation failed");
+    allocator::init_heap(
+        memory::MAPPER.lock().as_mut().unwrap(),
+        &mut frame_allocator,
+    )
+    .expect("heap initialisation failed");
+
+    // Frames can only be shared between mappings (e.g. copy-on-write pages) once they are reference counted
+    memory::refcount::init();
+
+    // Swap pages of on-demand regions out when memory runs low. There is no disk driver yet, so a 1MiB RAM disk
+    // has to do for now.
+    let ram_disk = RamDisk::new(256).expect("failed to create the swap RAM disk");
+    memory::swap::init(Box::new(ram_disk));
+
+    // The bootloader maps the VGA text buffer with the default write-back memory type. Writes to the screen are
+    // faster through a write-combining mapping, which lets the CPU collect them and send them out in bursts.
+    let vga_mapping = unsafe {
+        memory::map_mmio(
+            PhysAddr::new(0xb8000),
+            80 * 25 * 2,
+            CacheMode::WriteCombining,
+        )
+    }
+    .expect("failed to map the VGA buffer");
+    unsafe { vga_buffer::set_buffer(vga_mapping) };
+
+    // Now that memory management is up, move the double fault handler onto a stack with a guard page
+    gdt::init_ist_stacks();
+    // The layout is random, so write it down for debugging
+    memory::log_layout();
+
+    // Check that no page is both writable and executable. The offending ranges are listed on serial.
+    let wx_pages = memory::audit_wx();
+    if wx_pages > 0 {
+        println!("W^X audit: {} writable and executable pages", wx_pages);
+    }
 
     // Use a box to allocate a value to the heap
     let heap_value = Box::new(41);
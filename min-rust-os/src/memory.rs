@@ -26,16 +26,192 @@
 // let frame = recursive_page_table.translate_page(page);
 // frame.map(|frame| frame.start_address() + u64::from(addr.page_offset()))
 
+use crate::random;
+use bitmap::BitmapFrameAllocator;
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::arch::asm;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
 use x86_64::{
-    registers::control::Cr3,
-    structures::paging::page_table::FrameError,
+    align_down, align_up,
+    instructions::{interrupts, tlb},
+    registers::control::{Cr0, Cr0Flags, Cr3},
+    registers::model_specific::{Efer, EferFlags, Msr},
+    structures::idt::PageFaultErrorCode,
+    structures::paging::page_table::PageTableEntry,
     structures::paging::{
-        FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PhysFrame, Size4KiB,
+        mapper::{MapToError, MappedFrame, TranslateResult, UnmapError},
+        page::PageRange,
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTable,
+        PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB, Translate,
     },
     PhysAddr, VirtAddr,
 };
 
+pub mod address_space;
+pub mod bitmap;
+pub mod buddy;
+pub mod cow;
+pub mod demand_paging;
+pub mod refcount;
+pub mod shared;
+pub mod swap;
+pub mod vma;
+pub mod walk;
+
+// The frame allocator that owns the physical memory once the kernel is up. Page tables for new address spaces
+// are allocated from it and given back to it when the address space is dropped, so it has to live in a static.
+pub static FRAME_ALLOCATOR: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
+
+// The mapper for the kernel's page table. Code that can't be handed a mapper, like the page fault handler,
+// uses it to create new mappings. Lock it only for as long as a mapping is being changed.
+// Code that needs more than one of the memory locks takes them in this order: MAPPER, vma::KERNEL_VMAS, the swap
+// state of the swap module and FRAME_ALLOCATOR last. The page fault handlers only ever try_lock, so they give up
+// instead of deadlocking when the fault happened while one of the locks was held.
+pub static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+
+// Flags for kernel data pages: readable and writable, but never executable
+pub const KERNEL_DATA_FLAGS: PageTableFlags = PageTableFlags::from_bits_truncate(
+    PageTableFlags::PRESENT.bits()
+        | PageTableFlags::WRITABLE.bits()
+        | PageTableFlags::NO_EXECUTE.bits(),
+);
+
+// Where the bootloader mapped the complete physical memory, set by init_kernel_mapper
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+// The physical address of the kernel's level 4 table, see kernel_level_4_frame
+static KERNEL_LEVEL_4_TABLE: AtomicU64 = AtomicU64::new(0);
+
+// The bootloader's memory map, kept by init_frame_allocator for MemoryStats
+static MEMORY_MAP: Mutex<Option<&'static MemoryMap>> = Mutex::new(None);
+
+// Makes the CPU enforce the permissions in the page tables. Setting EFER.NXE makes the NO_EXECUTE flag take
+// effect (without it, the flag is a reserved bit and every access to such a page faults). Setting CR0.WP makes
+// read only pages read only for the kernel as well, which copy-on-write relies on.
+pub fn enable_nx() {
+    unsafe {
+        Efer::update(|flags| *flags |= EferFlags::NO_EXECUTE_ENABLE);
+        Cr0::update(|flags| *flags |= Cr0Flags::WRITE_PROTECT);
+    }
+}
+
+// Pages that can be written to must never be executable, or a bug that writes to memory could inject code.
+// Returns `flags` with NO_EXECUTE added if they make the page writable. Code that picks the flags of a page at
+// runtime, like the page fault handlers, passes them through this.
+pub fn without_wx(flags: PageTableFlags) -> PageTableFlags {
+    if flags.contains(PageTableFlags::WRITABLE) {
+        flags | PageTableFlags::NO_EXECUTE
+    } else {
+        flags
+    }
+}
+
+// The IA32_PAT model specific register, which holds the eight memory types page table entries can select
+const IA32_PAT: u32 = 0x277;
+// Memory type encodings for the PAT entries
+const PAT_UNCACHEABLE: u64 = 0x00;
+const PAT_WRITE_COMBINING: u64 = 0x01;
+const PAT_WRITE_THROUGH: u64 = 0x04;
+const PAT_WRITE_BACK: u64 = 0x06;
+const PAT_UNCACHED_MINUS: u64 = 0x07;
+// In a level 1 entry, bit 7 is the PAT bit, which selects one of the upper four PAT entries. The x86_64 crate only
+// knows the bit as HUGE_PAGE.
+pub const PAT_4KIB: PageTableFlags = PageTableFlags::HUGE_PAGE;
+
+// How the CPU caches accesses to a mapping
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    // Normal cached memory, the default for RAM
+    WriteBack,
+    // Writes are collected in a buffer and sent out in bursts, reads are not cached. Much faster than uncached
+    // for memory that is mostly written in big chunks, like framebuffers.
+    WriteCombining,
+    // Reads are cached, writes go straight to memory
+    WriteThrough,
+    // Every access goes to memory (or the device) in program order, as device registers require
+    Uncached,
+}
+
+impl CacheMode {
+    // The flags of a 4KiB page table entry that select this cache mode. An entry picks one of the eight PAT
+    // entries with its PAT, PCD (NO_CACHE) and PWT (WRITE_THROUGH) bits. Write-through needs the PAT bit, which
+    // the mapper refuses to set because it means HUGE_PAGE on the upper levels, so map the page without PAT_4KIB
+    // and add it with update_flags afterwards (see map_mmio).
+    pub fn flags(self) -> PageTableFlags {
+        match self {
+            CacheMode::WriteBack => PageTableFlags::empty(),
+            CacheMode::WriteCombining => PageTableFlags::WRITE_THROUGH,
+            CacheMode::WriteThrough => PAT_4KIB | PageTableFlags::WRITE_THROUGH,
+            CacheMode::Uncached => PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+        }
+    }
+}
+
+// Programs the PAT so that the cache modes map to the flags returned by CacheMode::flags. Only entry 1 changes,
+// from write-through to write-combining, so a mapping that only sets PWT becomes write-combining. The bootloader
+// doesn't create such mappings and init calls this before the kernel maps anything, so only mappings made with
+// CacheMode::WriteCombining use the entry. Every other entry keeps its power on value: a mapping with only PCD
+// (NO_CACHE) set stays uncached (UC-), and write-through moves to entry 5, which is selected with the PAT bit.
+// The PAT must not change while memory types are cached, so the update follows the procedure of the Intel SDM
+// (Vol. 3A, 11.11.8): disable caching, flush the caches, write the PAT, flush again and enable caching.
+pub fn init_pat() {
+    let entries = [
+        PAT_WRITE_BACK,
+        PAT_WRITE_COMBINING,
+        PAT_UNCACHED_MINUS,
+        PAT_UNCACHEABLE,
+        PAT_WRITE_BACK,
+        PAT_WRITE_THROUGH,
+        PAT_UNCACHED_MINUS,
+        PAT_UNCACHEABLE,
+    ];
+    let pat = entries
+        .iter()
+        .enumerate()
+        .fold(0u64, |pat, (index, &entry)| pat | entry << (index * 8));
+
+    interrupts::without_interrupts(|| unsafe {
+        // No fill mode: the caches are neither filled nor used for new accesses
+        Cr0::update(|flags| {
+            flags.insert(Cr0Flags::CACHE_DISABLE);
+            flags.remove(Cr0Flags::NOT_WRITE_THROUGH);
+        });
+        asm!("wbinvd", options(nostack, preserves_flags));
+        // The TLB may still hold translations with the old memory types
+        tlb::flush_all();
+        Msr::new(IA32_PAT).write(pat);
+        asm!("wbinvd", options(nostack, preserves_flags));
+        tlb::flush_all();
+        Cr0::update(|flags| flags.remove(Cr0Flags::CACHE_DISABLE));
+    });
+}
+
+// Walks the active page table and reports every mapping that is both writable and executable over serial.
+// init_frame_allocator removes the ones the bootloader created, so any page found here was mapped by the kernel.
+// Returns the number of offending 4KiB pages. The flags are combined along the walk, so a page is only counted if
+// every level makes it writable and none of them makes it non executable.
+pub fn audit_wx() -> u64 {
+    let offset = physical_memory_offset();
+    let (level_4_frame, _) = Cr3::read();
+    let level_4_table: *const PageTable =
+        (offset + level_4_frame.start_address().as_u64()).as_ptr();
+    let mut pages = 0;
+    let result = unsafe {
+        walk::for_each_mapping(&*level_4_table, offset, |mapping| {
+            if mapping.flags.contains(PageTableFlags::WRITABLE)
+                && !mapping.flags.contains(PageTableFlags::NO_EXECUTE)
+            {
+                crate::serial_println!("W^X violation: {}", mapping);
+                pages += mapping.size / Size4KiB::SIZE;
+            }
+            Ok::<(), ()>(())
+        })
+    };
+    result.unwrap();
+    pages
+}
+
 // Returns a mutable reference to the active level 4 table.
 // This function is unsafe because the caller must guarantee that the
 // complete physical memory is mapped to virtual memory at the passed
@@ -78,7 +254,7 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Opt
     let mut frame = level_4_page_table_frame;
 
     // traverse multi level page table
-    for &index in &table_indexes {
+    for (level, &index) in table_indexes.iter().enumerate() {
         // convert the frame into a page table reference
         let virt = physical_memory_offset + frame.start_address().as_u64();
         let table_ptr: *const PageTable = virt.as_ptr();
@@ -86,11 +262,26 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Opt
 
         // read the page table entry and update `frame`
         let entry = &table[index];
-        frame = match entry.frame() {
-            Ok(frame) => frame,
-            Err(FrameError::FrameNotPresent) => return None,
-            Err(FrameError::HugeFrame) => panic!("huge pages are not supported"),
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        // A huge page entry maps the frame directly, so the walk stops early. An entry in a level 3 table
+        // maps a 1GiB page and an entry in a level 2 table maps a 2MiB page. The remaining bits of the
+        // address (not only the lowest 12 bits) are the offset into that page. Bit 7 only means HUGE_PAGE in
+        // those two levels, in a level 1 table it is the PAT bit of a normal 4KiB page.
+        let huge_page_size = match level {
+            1 => Some(Size1GiB::SIZE),
+            2 => Some(Size2MiB::SIZE),
+            _ => None,
         };
+        if let Some(page_size) = huge_page_size {
+            if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                // Bit 12 of a huge page entry is its PAT bit, so it is not part of the frame address
+                let page_start = entry.addr().align_down(page_size);
+                return Some(page_start + (addr.as_u64() & (page_size - 1)));
+            }
+        }
+        frame = PhysFrame::containing_address(entry.addr());
     }
 
     // calculate physical address by adding page offset
@@ -109,6 +300,286 @@ pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static>
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
 
+// Stores the mapper for the active level 4 table in MAPPER. Like init, this function is unsafe because the
+// caller must guarantee that the complete physical memory is mapped at the passed `physical_memory_offset`,
+// and it must be only called once. It must not be combined with init either, since both hand out a `&mut`
+// reference to the same level 4 table.
+pub unsafe fn init_kernel_mapper(physical_memory_offset: VirtAddr) {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
+    let (level_4_frame, _) = Cr3::read();
+    KERNEL_LEVEL_4_TABLE.store(level_4_frame.start_address().as_u64(), Ordering::Relaxed);
+    *MAPPER.lock() = Some(init(physical_memory_offset));
+}
+
+// The virtual address at which the complete physical memory is mapped
+pub fn physical_memory_offset() -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed))
+}
+
+// The frame of the level 4 table that MAPPER manages, which stays the kernel's table even while another
+// AddressSpace is active. Returns `None` before init_kernel_mapper was called.
+pub fn kernel_level_4_frame() -> Option<PhysFrame> {
+    match KERNEL_LEVEL_4_TABLE.load(Ordering::Relaxed) {
+        0 => None,
+        addr => Some(PhysFrame::containing_address(PhysAddr::new(addr))),
+    }
+}
+
+// Tries to resolve a page fault at the given address. Returns true if the faulting access can simply be
+// retried, and false if the fault is a real error.
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    swap::swap_in(addr, error_code)
+        || demand_paging::map_on_demand(addr, error_code)
+        || cow::handle_cow_fault(addr, error_code)
+}
+
+// Runs `f` with a mapper for the address space that is currently active. This is the kernel mapper unless
+// some other AddressSpace was activated, in which case a temporary mapper for its level 4 table is created.
+// Returns `None` without calling `f` if the kernel mapper is not set up or currently locked, so this can be
+// used from the page fault handler.
+fn with_active_mapper<R>(f: impl FnOnce(&mut OffsetPageTable) -> R) -> Option<R> {
+    let mut kernel_mapper = MAPPER.try_lock()?;
+    let kernel_mapper = kernel_mapper.as_mut()?;
+
+    let offset = physical_memory_offset();
+    let (active_frame, _) = Cr3::read();
+    let active_table: *mut PageTable =
+        (offset + active_frame.start_address().as_u64()).as_mut_ptr();
+    if kernel_mapper.level_4_table() as *mut PageTable == active_table {
+        Some(f(kernel_mapper))
+    } else {
+        // Holding the lock of the kernel mapper makes sure nobody else changes the shared kernel tables meanwhile
+        let mut mapper = unsafe { OffsetPageTable::new(&mut *active_table, offset) };
+        Some(f(&mut mapper))
+    }
+}
+
+// Writes every mapping of the active page table to `writer`, with contiguous ranges merged. Pass the serial
+// port or the VGA writer to inspect the address space, e.g. `dump_mappings(&mut *serial::SERIAL1.lock())`.
+// The kernel mapper must be set up first, since the tables are read through the physical memory mapping.
+pub fn dump_mappings(writer: &mut impl fmt::Write) -> fmt::Result {
+    let offset = physical_memory_offset();
+    let (level_4_frame, _) = Cr3::read();
+    let level_4_table: *const PageTable =
+        (offset + level_4_frame.start_address().as_u64()).as_ptr();
+    // The tables are only read, so unlike active_level_4_table this doesn't create a second `&mut` reference
+    unsafe { walk::write_mappings(&*level_4_table, offset, writer) }
+}
+
+// Logs the regions of the kernel window and where the randomness for their placement came from to serial
+pub fn log_layout() {
+    crate::serial_println!("kernel layout (random source: {:?}):", random::source());
+    for vma in vma::KERNEL_VMAS.lock().iter() {
+        crate::serial_println!(
+            "  {:#018x}-{:#018x} {}",
+            vma.start.as_u64(),
+            vma.end().as_u64() - 1,
+            vma.name
+        );
+    }
+}
+
+// Sets up the global frame allocator from the bootloader's memory map.
+// This function is unsafe for the same reasons as BitmapFrameAllocator::init, and it must be only called once.
+pub unsafe fn init_frame_allocator(
+    memory_map: &'static MemoryMap,
+    physical_memory_offset: VirtAddr,
+) {
+    *FRAME_ALLOCATOR.lock() = Some(BitmapFrameAllocator::init(
+        memory_map,
+        physical_memory_offset,
+    ));
+    *MEMORY_MAP.lock() = Some(memory_map);
+    populate_kernel_window(physical_memory_offset);
+    protect_physical_memory_window(memory_map, physical_memory_offset);
+    protect_boot_mappings(physical_memory_offset);
+}
+
+// The bootloader maps the physical memory window writable and executable. Nothing runs code through the window,
+// so its level 4 entries get the NO_EXECUTE flag, which applies to every page below them. The bootloader gives
+// the window level 4 entries of its own, which is checked first, so that no other mapping is affected.
+unsafe fn protect_physical_memory_window(memory_map: &MemoryMap, physical_memory_offset: VirtAddr) {
+    const LEVEL_4_ENTRY_SIZE: u64 = Size1GiB::SIZE * 512;
+
+    let (level_4_frame, _) = Cr3::read();
+    let level_4_table: &mut PageTable =
+        &mut *table_ptr(physical_memory_offset, level_4_frame.start_address());
+    let max_phys_addr = memory_map
+        .iter()
+        .map(|r| r.range.end_addr())
+        .max()
+        .unwrap_or(0);
+    if max_phys_addr == 0 {
+        return;
+    }
+    // The bootloader maps the window with 2MiB pages, up to and including the one that contains max_phys_addr.
+    // The addresses are inclusive, since the last entry might end at the very top of the address space.
+    let window_start = physical_memory_offset.as_u64();
+    let window_last = window_start + (align_up(max_phys_addr + 1, Size2MiB::SIZE) - 1);
+    let entries_start = align_down(window_start, LEVEL_4_ENTRY_SIZE);
+    let entries_last = align_down(window_last, LEVEL_4_ENTRY_SIZE) + (LEVEL_4_ENTRY_SIZE - 1);
+
+    let shared = walk::for_each_mapping(level_4_table, physical_memory_offset, |mapping| {
+        let start = mapping.start.as_u64();
+        let last = start + (mapping.size - 1);
+        let in_entries = start <= entries_last && last >= entries_start;
+        let in_window = start >= window_start && last <= window_last;
+        if in_entries && !in_window {
+            Err(mapping)
+        } else {
+            Ok(())
+        }
+    });
+    if let Err(mapping) = shared {
+        panic!(
+            "{} shares a level 4 entry with the physical memory window",
+            mapping
+        );
+    }
+
+    let first = usize::from(VirtAddr::new(window_start).p4_index());
+    let last = usize::from(VirtAddr::new(window_last).p4_index());
+    for index in first..=last {
+        let entry = &mut level_4_table[index];
+        entry.set_flags(entry.flags() | PageTableFlags::NO_EXECUTE);
+    }
+    tlb::flush_all();
+}
+
+// Besides the physical memory window, the bootloader maps its stack, which the kernel keeps running on, and the
+// boot information writable and executable. Nothing runs code from there, so every page that ends up writable but
+// executable gets the NO_EXECUTE flag.
+unsafe fn protect_boot_mappings(physical_memory_offset: VirtAddr) {
+    let (level_4_frame, _) = Cr3::read();
+    let level_4_table = &mut *table_ptr(physical_memory_offset, level_4_frame.start_address());
+    protect_writable_entries(
+        level_4_table,
+        4,
+        PageTableFlags::WRITABLE,
+        physical_memory_offset,
+    );
+    tlb::flush_all();
+}
+
+// Sets NO_EXECUTE on every page below `table` that is writable and executable. A page is only writable if every
+// level says so, and no longer executable once an entry above it has NO_EXECUTE.
+unsafe fn protect_writable_entries(
+    table: &mut PageTable,
+    level: u8,
+    parent_flags: PageTableFlags,
+    physical_memory_offset: VirtAddr,
+) {
+    for entry in table.iter_mut() {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::NO_EXECUTE) {
+            continue;
+        }
+        let writable = flags & parent_flags & PageTableFlags::WRITABLE;
+        if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+            entry.set_flags(flags | (without_wx(writable) - writable));
+        } else {
+            let table = &mut *table_ptr(physical_memory_offset, entry.addr());
+            protect_writable_entries(table, level - 1, writable, physical_memory_offset);
+        }
+    }
+}
+
+// Gives every level 4 entry that covers the kernel VMA window a level 3 table. AddressSpace::new shares the kernel
+// mappings by copying the level 4 entries of the kernel's table, so a region that is reserved later on shows up
+// in every address space only if its level 4 entry already exists. The window spans 16 entries, so this costs
+// 16 frames.
+unsafe fn populate_kernel_window(physical_memory_offset: VirtAddr) {
+    // Nothing else touches these entries yet, so a short lived reference next to the kernel mapper's is fine
+    let (level_4_frame, _) = Cr3::read();
+    let level_4_table: &mut PageTable =
+        &mut *(physical_memory_offset + level_4_frame.start_address().as_u64()).as_mut_ptr();
+    let first = usize::from(VirtAddr::new(vma::KERNEL_VMA_START).p4_index());
+    let last = usize::from(VirtAddr::new(vma::KERNEL_VMA_END - 1).p4_index());
+
+    for index in first..=last {
+        let entry = &mut level_4_table[index];
+        if !entry.is_unused() {
+            continue;
+        }
+        let frame = GlobalFrameAllocator
+            .allocate_frame()
+            .expect("no frame for a level 3 table of the kernel window");
+        let table: &mut PageTable =
+            &mut *(physical_memory_offset + frame.start_address().as_u64()).as_mut_ptr();
+        table.zero();
+        entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+    }
+}
+
+// A summary of the physical memory, in bytes. The first part comes from the bootloader's memory map and never
+// changes, the frame counts show how much of the usable memory the frame allocator has handed out so far.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MemoryStats {
+    // The RAM in use by the kernel or available to it: the sum of usable, kernel, page_tables and bootloader.
+    // Reserved regions are not RAM the kernel could ever use, so they are left out.
+    pub total: u64,
+    // Memory the frame allocator manages
+    pub usable: u64,
+    // Memory that is not available to the kernel: firmware and ACPI regions, bad memory and holes
+    pub reserved: u64,
+    // The kernel image and the boot stack
+    pub kernel: u64,
+    // The page tables the bootloader created for us
+    pub page_tables: u64,
+    // The bootloader itself and the boot information it passes to the kernel
+    pub bootloader: u64,
+    pub allocated_frames: usize,
+    pub free_frames: usize,
+}
+
+impl MemoryStats {
+    // Sums up the regions of the given memory map by type. The frame counts are left at 0.
+    pub fn from_memory_map(memory_map: &MemoryMap) -> Self {
+        let mut stats = MemoryStats::default();
+        for region in memory_map.iter() {
+            let size = region.range.end_addr() - region.range.start_addr();
+            match region.region_type {
+                MemoryRegionType::Usable => stats.usable += size,
+                MemoryRegionType::Kernel | MemoryRegionType::KernelStack => stats.kernel += size,
+                MemoryRegionType::PageTable => stats.page_tables += size,
+                MemoryRegionType::Bootloader
+                | MemoryRegionType::BootInfo
+                | MemoryRegionType::Package => stats.bootloader += size,
+                _ => stats.reserved += size,
+            }
+        }
+        stats.total = stats.usable + stats.kernel + stats.page_tables + stats.bootloader;
+        stats
+    }
+
+    // Collects the statistics of the running system. Returns `None` before init_frame_allocator was called.
+    pub fn current() -> Option<Self> {
+        let memory_map = (*MEMORY_MAP.lock())?;
+        let mut stats = MemoryStats::from_memory_map(memory_map);
+        let frame_allocator = FRAME_ALLOCATOR.lock();
+        let frame_allocator = frame_allocator.as_ref()?;
+        stats.allocated_frames = frame_allocator.used_frames();
+        stats.free_frames = frame_allocator.free_frames();
+        Some(stats)
+    }
+}
+
+impl fmt::Display for MemoryStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "total:       {:>8} KiB", self.total / 1024)?;
+        writeln!(f, "usable:      {:>8} KiB", self.usable / 1024)?;
+        writeln!(f, "kernel:      {:>8} KiB", self.kernel / 1024)?;
+        writeln!(f, "page tables: {:>8} KiB", self.page_tables / 1024)?;
+        writeln!(f, "bootloader:  {:>8} KiB", self.bootloader / 1024)?;
+        writeln!(f, "reserved:    {:>8} KiB", self.reserved / 1024)?;
+        write!(
+            f,
+            "frames:      {} allocated, {} free",
+            self.allocated_frames, self.free_frames
+        )
+    }
+}
+
 // maps a given virtual page to 0xb8000, the physical frame of the VGA text buffer.
 // We choose that frame because it allows us to easily test if the mapping was created correctly:
 // We just need to write to the newly mapped page and see whether we see the write appear on the screen.
@@ -135,6 +606,391 @@ pub fn create_example_mapping(
     map_to_result.expect("map_to failed").flush();
 }
 
+// Maps the virtual range starting at `start` with 2MiB pages backed by freshly allocated 2MiB frames.
+// A single 2MiB entry replaces a whole level 1 table of 512 entries, so large kernel regions like the heap
+// take up far fewer TLB entries. Both `start` and `size` must be 2MiB aligned. The frame allocator has to be
+// able to hand out 4KiB frames as well, since the mapper might need to create new page tables.
+pub fn map_huge_range<A>(
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+    mapper: &mut impl Mapper<Size2MiB>,
+    frame_allocator: &mut A,
+) -> Result<(), MapToError<Size2MiB>>
+where
+    A: FrameAllocator<Size4KiB> + FrameAllocator<Size2MiB>,
+{
+    assert!(
+        start.is_aligned(Size2MiB::SIZE) && size % Size2MiB::SIZE == 0,
+        "huge page ranges must be 2MiB aligned"
+    );
+    let start_page: Page<Size2MiB> = Page::containing_address(start);
+    let end_page: Page<Size2MiB> = Page::containing_address(start + size - 1u64);
+
+    for page in Page::range_inclusive(start_page, end_page) {
+        let frame: PhysFrame<Size2MiB> = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        // The mapper sets the HUGE_PAGE flag for us when mapping a 2MiB page
+        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+    }
+
+    Ok(())
+}
+
+// Maps `size` bytes of existing physical memory starting at `phys` to the virtual range starting at `virt`
+// with 2MiB pages, for example a window onto the physical memory. All three values must be 2MiB aligned.
+// This function is unsafe because the caller must guarantee that the physical range may be accessed
+// through the new mapping without breaking any other users of that memory.
+pub unsafe fn map_huge_physical(
+    phys: PhysAddr,
+    virt: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+    mapper: &mut impl Mapper<Size2MiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size2MiB>> {
+    assert!(
+        phys.is_aligned(Size2MiB::SIZE)
+            && virt.is_aligned(Size2MiB::SIZE)
+            && size % Size2MiB::SIZE == 0,
+        "huge page ranges must be 2MiB aligned"
+    );
+
+    for offset in (0..size).step_by(Size2MiB::SIZE as usize) {
+        let page: Page<Size2MiB> = Page::containing_address(virt + offset);
+        let frame: PhysFrame<Size2MiB> = PhysFrame::containing_address(phys + offset);
+        mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+    }
+
+    Ok(())
+}
+
+// Allocates a kernel stack of `pages` pages and returns its top, which is the initial stack pointer since stacks
+// grow downwards. The stack gets its own region from the VMA manager with one extra page at the bottom. That
+// guard page is never mapped, so a stack overflow faults right away instead of silently corrupting the memory
+// below the stack. Returns `None` if there is no virtual or physical memory left for the stack.
+pub fn alloc_kernel_stack(pages: u64) -> Option<VirtAddr> {
+    let stack = vma::KERNEL_VMAS
+        .lock()
+        .reserve_anywhere("kernel stack", (pages + 1) * Size4KiB::SIZE, Size4KiB::SIZE)
+        .ok()?;
+
+    let guard_page: Page = Page::containing_address(stack.start);
+    let stack_pages = Page::range(guard_page + 1, guard_page + 1 + pages);
+    let mut mapped = 0;
+    for page in stack_pages {
+        if map_reclaimed_frame(page, KERNEL_DATA_FLAGS).is_err() {
+            break;
+        }
+        mapped += 1;
+    }
+
+    // If we ran out of memory half way through, give back what we got so far
+    if mapped < pages {
+        let mut mapper = MAPPER.lock();
+        if let Some(mapper) = mapper.as_mut() {
+            let partial = Page::range(guard_page + 1, guard_page + 1 + mapped);
+            unsafe { unmap_range(mapper, partial, &mut GlobalFrameAllocator) }
+                .expect("failed to unmap a partial kernel stack");
+        }
+        vma::KERNEL_VMAS.lock().release(stack.start);
+        return None;
+    }
+
+    Some(stack.end())
+}
+
+// Frees a stack allocated with alloc_kernel_stack, given its top.
+// This function is unsafe because the caller must guarantee that the stack is no longer in use.
+pub unsafe fn free_kernel_stack(top: VirtAddr) {
+    let mut mapper = MAPPER.lock();
+    let mapper = mapper.as_mut().expect("kernel mapper not initialised");
+    let mut vmas = vma::KERNEL_VMAS.lock();
+    let stack = match vmas.find(top - 1u64) {
+        Some(stack) if stack.name == "kernel stack" && stack.end() == top => stack,
+        _ => panic!("{:?} is not the top of a kernel stack", top),
+    };
+
+    // The guard page was never mapped, unmap_range skips it
+    let pages = Page::range(
+        Page::containing_address(stack.start),
+        Page::containing_address(stack.end()),
+    );
+    unmap_range(mapper, pages, &mut GlobalFrameAllocator).expect("failed to unmap a kernel stack");
+    vmas.release(stack.start);
+}
+
+// Above this many pages, flushing the whole TLB is cheaper than invalidating every page on its own
+const FLUSH_ALL_THRESHOLD: u64 = 32;
+
+// Unmaps every page in `pages` and gives the frames back to `frame_deallocator`. Pages that are not mapped are
+// skipped, so the range may contain holes like guard pages or untouched on-demand pages. Copy-on-write and shared
+// frames are only freed once their last mapping (and for shared frames, their last SharedFrame handle) is gone.
+// Level 1 and level 2 tables that end up empty are freed as well. Level 3 tables are kept even if they are empty,
+// because their level 4 entries are shared with every address space (see AddressSpace). Fails if a page in the
+// range is part of a huge page.
+// This function is unsafe because the caller must guarantee that the pages are not used anymore, and that the
+// frames were allocated from `frame_deallocator` (so MMIO windows must go through unmap_mmio instead).
+pub unsafe fn unmap_range(
+    mapper: &mut OffsetPageTable,
+    pages: PageRange,
+    frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+) -> Result<(), UnmapError> {
+    unmap_pages(mapper, pages, |frame, flags| {
+        let shared = (flags.contains(cow::COW) || flags.contains(shared::SHARED))
+            && refcount::decrement(frame) > 0;
+        if !shared {
+            frame_deallocator.deallocate_frame(frame);
+        }
+    })
+}
+
+// Removes the mappings of `pages` and calls `release` with the frame and flags of every mapped page, frees the
+// page tables that became empty and flushes the TLB
+unsafe fn unmap_pages(
+    mapper: &mut OffsetPageTable,
+    pages: PageRange,
+    mut release: impl FnMut(PhysFrame, PageTableFlags),
+) -> Result<(), UnmapError> {
+    let page_count = pages.end - pages.start;
+    let flush_all = page_count > FLUSH_ALL_THRESHOLD;
+
+    for page in pages {
+        let flags = match mapper.translate(page.start_address()) {
+            // The mapper refuses to unmap a 4KiB page with the PAT bit, since it takes it for HUGE_PAGE
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size4KiB(_),
+                flags,
+                ..
+            } if flags.contains(PAT_4KIB) => {
+                mapper
+                    .update_flags(page, flags - PAT_4KIB)
+                    .expect("translate found the page")
+                    .ignore();
+                flags - PAT_4KIB
+            }
+            TranslateResult::Mapped { flags, .. } => flags,
+            TranslateResult::NotMapped => {
+                // A page that was swapped out doesn't have a frame anymore, but its swap slot must be freed
+                swap::discard(mapper, page.start_address());
+                continue;
+            }
+            TranslateResult::InvalidFrameAddress(addr) => {
+                return Err(UnmapError::InvalidFrameAddress(addr))
+            }
+        };
+        let (frame, flush) = mapper.unmap(page)?;
+        // invlpg for every page of a large range takes longer than reloading CR3 once at the end
+        if flush_all {
+            flush.ignore();
+        } else {
+            flush.flush();
+        }
+        release(frame, flags);
+    }
+
+    // The CPU also caches entries of the upper level tables, so freeing a table needs a full flush as well
+    let freed_tables = free_empty_tables(mapper, pages);
+    if flush_all || freed_tables {
+        // Writing CR3 drops every cached translation that isn't global
+        tlb::flush_all();
+    }
+    Ok(())
+}
+
+// Frees the level 1 tables and then the level 2 tables covering `pages` that don't map anything anymore.
+// Only tables that the global frame allocator handed out are freed, since that is where the kernel allocates its
+// page tables from. The tables the bootloader created are kept even if they are empty.
+// Returns whether any table was freed.
+unsafe fn free_empty_tables(mapper: &mut OffsetPageTable, pages: PageRange) -> bool {
+    if pages.start >= pages.end {
+        return false;
+    }
+    let mut freed = false;
+    let offset = physical_memory_offset();
+    let start = pages.start.start_address().as_u64();
+    let end = pages.end.start_address().as_u64();
+    let level_4_table = mapper.level_4_table();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let frame_allocator = match frame_allocator.as_mut() {
+        Some(frame_allocator) => frame_allocator,
+        None => return false,
+    };
+
+    // Level 1 tables cover 2MiB each, level 2 tables cover 1GiB each
+    for &(level, size) in &[(1, Size2MiB::SIZE), (2, Size1GiB::SIZE)] {
+        let mut chunk = align_down(start, size);
+        while chunk < end {
+            let addr = VirtAddr::new(chunk);
+            chunk += size;
+            // The entry in the table above points to the table that may be freed
+            let parent_entry = match table_entry(level_4_table, offset, addr, level + 1) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            let parent_flags = parent_entry.flags();
+            if !parent_flags.contains(PageTableFlags::PRESENT)
+                || parent_flags.contains(PageTableFlags::HUGE_PAGE)
+            {
+                continue;
+            }
+            let frame = PhysFrame::containing_address(parent_entry.addr());
+            if !frame_allocator.manages(frame) {
+                continue;
+            }
+            let table = &*table_ptr(offset, parent_entry.addr());
+            if table.iter().all(|entry| entry.is_unused()) {
+                parent_entry.set_unused();
+                frame_allocator.deallocate_frame(frame);
+                freed = true;
+            }
+        }
+    }
+    freed
+}
+
+// Returns the entry for `addr` in the level `level` table, or `None` if there is no such table because an entry
+// on the way down is not present or maps a huge page. The entry itself may be in any state.
+unsafe fn table_entry(
+    level_4_table: &mut PageTable,
+    offset: VirtAddr,
+    addr: VirtAddr,
+    level: u8,
+) -> Option<&mut PageTableEntry> {
+    let indexes = [
+        addr.p4_index(),
+        addr.p3_index(),
+        addr.p2_index(),
+        addr.p1_index(),
+    ];
+    let depth = 4 - level as usize;
+    let mut table = level_4_table;
+    for &index in &indexes[..depth] {
+        let flags = table[index].flags();
+        if !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::HUGE_PAGE) {
+            return None;
+        }
+        table = &mut *table_ptr(offset, table[index].addr());
+    }
+    Some(&mut table[indexes[depth]])
+}
+
+fn table_ptr(offset: VirtAddr, phys: PhysAddr) -> *mut PageTable {
+    (offset + phys.as_u64()).as_mut_ptr()
+}
+
+// Maps the `len` bytes of device memory starting at `phys` into a fresh kernel region and returns the virtual
+// address that corresponds to `phys`. `phys` doesn't have to be page aligned. Device registers should use
+// CacheMode::Uncached, so that every access reaches the device, while framebuffers are best mapped with
+// CacheMode::WriteCombining. The pages are never executable. Returns `None` if there is no virtual memory left
+// or the page tables for the mapping can't be allocated.
+// This function is unsafe because the caller must guarantee that the physical range belongs to a device. Mapping
+// ordinary RAM this way would alias the cached mappings of the same frames.
+pub unsafe fn map_mmio(phys: PhysAddr, len: u64, cache_mode: CacheMode) -> Option<VirtAddr> {
+    let first_frame: PhysFrame = PhysFrame::containing_address(phys);
+    let last_frame: PhysFrame = PhysFrame::containing_address(phys + (len.max(1) - 1));
+    let frames = PhysFrame::range_inclusive(first_frame, last_frame);
+    let size = (last_frame - first_frame + 1) * Size4KiB::SIZE;
+
+    let mut mapper = MAPPER.lock();
+    let mapper = mapper.as_mut()?;
+    let window = vma::KERNEL_VMAS
+        .lock()
+        .reserve_anywhere("mmio", size, Size4KiB::SIZE)
+        .ok()?;
+
+    let flags = KERNEL_DATA_FLAGS | cache_mode.flags();
+
+    let first_page: Page = Page::containing_address(window.start);
+    let mut mapped = 0;
+    for (page, frame) in Page::range(first_page, first_page + size / Size4KiB::SIZE).zip(frames) {
+        match mapper.map_to(page, frame, flags - PAT_4KIB, &mut GlobalFrameAllocator) {
+            Ok(flush) => flush.flush(),
+            Err(_) => break,
+        }
+        mapped += 1;
+        if flags.contains(PAT_4KIB) {
+            mapper
+                .update_flags(page, flags)
+                .expect("the page was just mapped")
+                .flush();
+        }
+    }
+
+    // Undo a partial mapping. The frames belong to the device, so they are not given to the frame allocator.
+    if mapped * Size4KiB::SIZE < size {
+        let _ = unmap_pages(
+            mapper,
+            Page::range(first_page, first_page + mapped),
+            |_, _| {},
+        );
+        vma::KERNEL_VMAS.lock().release(window.start);
+        return None;
+    }
+
+    Some(window.start + phys.as_u64() % Size4KiB::SIZE)
+}
+
+// Removes a mapping created by map_mmio. `addr` can be any address inside the mapping.
+// This function is unsafe because the caller must guarantee that the mapping is no longer used.
+pub unsafe fn unmap_mmio(addr: VirtAddr) {
+    let mut mapper = MAPPER.lock();
+    let mapper = mapper.as_mut().expect("kernel mapper not initialised");
+    let mut vmas = vma::KERNEL_VMAS.lock();
+    let window = match vmas.find(addr) {
+        Some(window) if window.name == "mmio" => window,
+        _ => panic!("{:?} is not part of an MMIO mapping", addr),
+    };
+
+    let first_page: Page = Page::containing_address(window.start);
+    let pages = Page::range(first_page, first_page + window.size / Size4KiB::SIZE);
+    // The frames belong to the device, so they are not given to the frame allocator
+    unmap_pages(mapper, pages, |_, _| {}).expect("failed to unmap an MMIO window");
+    vmas.release(window.start);
+}
+
+// Maps the given page to a newly allocated frame. The frame is given back if the mapping fails.
+fn map_fresh_frame(
+    mapper: &mut OffsetPageTable,
+    page: Page,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    let frame = GlobalFrameAllocator
+        .allocate_frame()
+        .ok_or(MapToError::FrameAllocationFailed)?;
+    match unsafe { mapper.map_to(page, frame, flags, &mut GlobalFrameAllocator) } {
+        Ok(flush) => {
+            flush.flush();
+            Ok(())
+        }
+        Err(err) => {
+            unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+            Err(err)
+        }
+    }
+}
+
+// Maps the given page to a frame from swap::allocate_frame, which evicts pageable pages if the frame allocator
+// has run out. Eviction changes the page tables, so the MAPPER lock must not be held by the caller. It is only
+// taken to map the frame, and if it is held anyway, the mapping fails like it does without a frame.
+pub(crate) fn map_reclaimed_frame(
+    page: Page,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    let frame = swap::allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
+    let mut mapper = MAPPER.try_lock();
+    let result = match mapper.as_mut().and_then(|mapper| mapper.as_mut()) {
+        Some(mapper) => unsafe { mapper.map_to(page, frame, flags, &mut GlobalFrameAllocator) }
+            .map(|flush| flush.flush()),
+        None => Err(MapToError::FrameAllocationFailed),
+    };
+    if result.is_err() {
+        unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+    }
+    result
+}
+
 // A dummy FrameAllocator that always returns `None`
 pub struct EmptyFrameAllocator;
 
@@ -146,6 +1002,27 @@ unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
     }
 }
 
+// A handle to the global FRAME_ALLOCATOR. It doesn't hold any state itself, so it can be created wherever
+// a FrameAllocator is needed, for example to pass it to Mapper::map_to. Every call takes the lock for the
+// duration of a single allocation only.
+pub struct GlobalFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        FRAME_ALLOCATOR.lock().as_mut()?.allocate_frame()
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for GlobalFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        FRAME_ALLOCATOR
+            .lock()
+            .as_mut()
+            .expect("frame allocator not initialised")
+            .deallocate_frame(frame)
+    }
+}
+
 // FrameAllocator that returns usable frames from the bootloader's memory map
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
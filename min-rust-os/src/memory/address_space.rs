@@ -0,0 +1,227 @@
+use super::{
+    cow,
+    shared::{self, SharedFrame},
+    swap, walk, GlobalFrameAllocator,
+};
+use core::fmt;
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{
+        mapper::{MapToError, UnmapError},
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+        PhysFrame, Size4KiB,
+    },
+    VirtAddr,
+};
+
+// Level 4 entries 256 to 511 cover the upper half of the virtual address space, which belongs to the kernel
+const KERNEL_HALF_START: usize = 256;
+const ENTRY_COUNT: usize = 512;
+
+// An address space with its own level 4 page table. The kernel part of the table is shared with the address
+// space that was active when it was created, by copying the level 4 entries. Since those entries point to the
+// same level 3 tables, later changes to the kernel mappings below them show up in every address space.
+// Everything else is private, so programs running in different address spaces can't see each other's memory.
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+    physical_memory_offset: VirtAddr,
+    // One bit for each level 4 entry that is shared with the kernel. Besides the upper half, this includes
+    // every lower half entry of the kernel's table that was in use when the address space was created: the
+    // bootloader we use maps the kernel image, its stack and the physical memory window into the lower half.
+    // The regions of the kernel VMA manager (the heap, stacks, MMIO windows) are in level 4 entries that exist
+    // from boot on. These entries must never be touched or freed by the address space.
+    kernel_entries: [u64; ENTRY_COUNT / 64],
+}
+
+impl AddressSpace {
+    // Create a new address space with a fresh level 4 table from the global frame allocator.
+    // Returns `None` if there is no frame left for the table. Panics if the kernel mapper is not set up.
+    // This function is unsafe because the caller must guarantee that the complete physical memory
+    // is mapped to virtual memory at the passed `physical_memory_offset`.
+    pub unsafe fn new(physical_memory_offset: VirtAddr) -> Option<Self> {
+        let level_4_frame = GlobalFrameAllocator.allocate_frame()?;
+        let mut address_space = AddressSpace {
+            level_4_frame,
+            physical_memory_offset,
+            kernel_entries: [0; ENTRY_COUNT / 64],
+        };
+
+        // The entries are copied from the kernel's table and not from the active one, which might be another
+        // address space with private mappings of its own. The kernel's table never has any, private mappings
+        // go through AddressSpace. We only read the table here, so we don't go through the kernel mapper.
+        let kernel_frame = super::kernel_level_4_frame().expect("kernel mapper not initialised");
+        let kernel_table = &*address_space.table_ptr(kernel_frame);
+        let table = &mut *address_space.table_ptr(level_4_frame);
+        table.zero();
+
+        for (index, entry) in kernel_table.iter().enumerate() {
+            if index >= KERNEL_HALF_START || !entry.is_unused() {
+                table[index] = entry.clone();
+                address_space.kernel_entries[index / 64] |= 1 << (index % 64);
+            }
+        }
+
+        Some(address_space)
+    }
+
+    // The physical frame of the level 4 table, i.e. the value that ends up in the CR3 register
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    // Returns whether this address space is the one the CPU is currently using
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    // Switch the CPU to this address space by loading its level 4 table into CR3. The PCD and PWT flags of
+    // CR3, which select the memory type of the level 4 table, are kept.
+    // Writing CR3 flushes all non global TLB entries, so no additional flush is necessary.
+    // This function is unsafe because the caller must make sure that this address space stays alive
+    // for as long as it is active, and that the code and stack in use are mapped in it.
+    pub unsafe fn activate(&self) {
+        let (_, flags) = Cr3::read();
+        Cr3::write(self.level_4_frame, flags);
+    }
+
+    // Maps the given page to the given frame in this address space. Page tables for the mapping are allocated
+    // from the global frame allocator. The page must lie in one of the private (non kernel) level 4 entries.
+    // This function is unsafe because the caller must guarantee that the frame is not already in use in a way
+    // that conflicts with the new mapping.
+    pub unsafe fn map_to(
+        &mut self,
+        page: Page,
+        frame: PhysFrame,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        assert!(
+            !self.is_kernel_entry(usize::from(page.p4_index())),
+            "{:?} lies in the kernel part of the address space",
+            page
+        );
+
+        let is_active = self.is_active();
+        let level_4_table = &mut *self.table_ptr(self.level_4_frame);
+        let mut mapper = OffsetPageTable::new(level_4_table, self.physical_memory_offset);
+        let flush = mapper.map_to(page, frame, flags, &mut GlobalFrameAllocator)?;
+        // The TLB only caches entries of the active address space
+        if is_active {
+            flush.flush();
+        } else {
+            flush.ignore();
+        }
+
+        Ok(())
+    }
+
+    // Maps the given page to a shared frame in this address space, see SharedFrame::map. The mapping keeps the
+    // frame alive until it is removed with unmap_shared or the address space is dropped.
+    // This function is unsafe for the same reason as SharedFrame::map.
+    pub unsafe fn map_shared(
+        &mut self,
+        page: Page,
+        frame: &SharedFrame,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        assert!(
+            !self.is_kernel_entry(usize::from(page.p4_index())),
+            "{:?} lies in the kernel part of the address space",
+            page
+        );
+
+        let level_4_table = &mut *self.table_ptr(self.level_4_frame);
+        let mut mapper = OffsetPageTable::new(level_4_table, self.physical_memory_offset);
+        frame.map(&mut mapper, page, flags)
+    }
+
+    // Maps the given page to `frame` as a copy-on-write page in this address space, see cow::map_cow. The mapping
+    // holds a reference to the frame until the page is written to or the address space is dropped.
+    // This function is unsafe for the same reason as cow::map_cow.
+    pub unsafe fn map_cow(
+        &mut self,
+        page: Page,
+        frame: PhysFrame,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        assert!(
+            !self.is_kernel_entry(usize::from(page.p4_index())),
+            "{:?} lies in the kernel part of the address space",
+            page
+        );
+
+        let level_4_table = &mut *self.table_ptr(self.level_4_frame);
+        let mut mapper = OffsetPageTable::new(level_4_table, self.physical_memory_offset);
+        cow::map_cow(&mut mapper, page, frame, flags)
+    }
+
+    // Removes a mapping made with map_shared and drops its reference to the frame
+    // This function is unsafe because the caller must guarantee that the page is not used anymore.
+    pub unsafe fn unmap_shared(&mut self, page: Page) -> Result<(), UnmapError> {
+        let level_4_table = &mut *self.table_ptr(self.level_4_frame);
+        let mut mapper = OffsetPageTable::new(level_4_table, self.physical_memory_offset);
+        shared::unmap(&mut mapper, page)
+    }
+
+    // Writes every mapping of this address space to `writer`, including the shared kernel mappings.
+    // See memory::dump_mappings for the format.
+    pub fn dump_mappings(&self, writer: &mut impl fmt::Write) -> fmt::Result {
+        unsafe {
+            let level_4_table = &*self.table_ptr(self.level_4_frame);
+            walk::write_mappings(level_4_table, self.physical_memory_offset, writer)
+        }
+    }
+
+    fn is_kernel_entry(&self, index: usize) -> bool {
+        self.kernel_entries[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    fn table_ptr(&self, frame: PhysFrame) -> *mut PageTable {
+        let virt = self.physical_memory_offset + frame.start_address().as_u64();
+        virt.as_mut_ptr()
+    }
+
+    // Gives the frame of the given page table back to the frame allocator, together with all the lower level
+    // tables it points to. Only page table frames are freed, since the mapped frames belong to whoever mapped
+    // them. The exception are reference counted frames: copy-on-write and shared mappings drop their reference
+    // here, so the frame is freed if this address space held the last one. Pages that were swapped out free
+    // their swap slot. Entries with the HUGE_PAGE flag map a frame directly instead of pointing to another table.
+    unsafe fn free_table(&self, frame: PhysFrame, level: u8) {
+        let table = &mut *self.table_ptr(frame);
+        for entry in table.iter_mut() {
+            let flags = entry.flags();
+            if !flags.contains(PageTableFlags::PRESENT) {
+                if level == 1 {
+                    swap::discard_entry(entry);
+                }
+                continue;
+            }
+            let child = PhysFrame::containing_address(entry.addr());
+            if level == 1 {
+                if flags.contains(cow::COW) || flags.contains(shared::SHARED) {
+                    shared::release(child);
+                }
+            } else if !flags.contains(PageTableFlags::HUGE_PAGE) {
+                self.free_table(child, level - 1);
+            }
+        }
+        GlobalFrameAllocator.deallocate_frame(frame);
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        // Freeing the tables of the active address space would pull the rug from under the CPU
+        assert!(!self.is_active(), "dropped the active address space");
+
+        unsafe {
+            let level_4_table = &*self.table_ptr(self.level_4_frame);
+            for (index, entry) in level_4_table.iter().enumerate() {
+                if !self.is_kernel_entry(index) && !entry.is_unused() {
+                    let level_3_frame = PhysFrame::containing_address(entry.addr());
+                    self.free_table(level_3_frame, 3);
+                }
+            }
+            GlobalFrameAllocator.deallocate_frame(self.level_4_frame);
+        }
+    }
+}
@@ -0,0 +1,230 @@
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::ops::Range;
+use core::slice;
+use x86_64::{
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size2MiB, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+const FRAME_SIZE: u64 = 4096;
+// Each word of the bitmap tracks 64 frames
+const FRAMES_PER_WORD: usize = 64;
+// A 2MiB frame spans 512 4KiB frames, which are 8 consecutive words of the bitmap
+const WORDS_PER_HUGE_FRAME: usize = (Size2MiB::SIZE / FRAME_SIZE) as usize / FRAMES_PER_WORD;
+
+// FrameAllocator that keeps one bit per physical frame. The bitmap is built once from the bootloader's
+// memory map, so allocating a frame no longer has to walk the memory map like BootInfoFrameAllocator does,
+// and frames can be handed back through the FrameDeallocator trait.
+pub struct BitmapFrameAllocator {
+    // The bitmap covers every frame from physical address 0 up to the end of the highest usable region.
+    // A set bit means the frame is in use (or isn't usable RAM at all), a cleared bit means it is free.
+    bitmap: &'static mut [u64],
+    // Frames that were never free to begin with must not be freed either, so deallocate_frame checks frames
+    // against the usable regions of the memory map and the frames of the bitmap itself
+    memory_map: &'static MemoryMap,
+    bitmap_frames: Range<usize>,
+    // Index of the word where the search for the next free frame starts. Everything before it is known
+    // to be in use, so we don't have to scan the whole bitmap from the beginning on every allocation.
+    next_word: usize,
+    total_frames: usize,
+    free_frames: usize,
+}
+
+impl BitmapFrameAllocator {
+    // Create a BitmapFrameAllocator from the passed memory map.
+    // The bitmap itself is stored in the first usable region that is large enough to hold it, and those frames
+    // are marked as used straight away. We access it through the physical memory mapping, so this function is
+    // unsafe because the caller must guarantee that the complete physical memory is mapped at the passed
+    // `physical_memory_offset`. Like BootInfoFrameAllocator::init, the caller must also guarantee that the
+    // usable frames of the memory map aren't already used somewhere else.
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let usable_regions = || {
+            memory_map
+                .iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+        };
+
+        // The bitmap needs one bit for every frame up to the last usable one
+        let frame_count = usable_regions()
+            .map(|r| r.range.end_frame_number)
+            .max()
+            .unwrap_or(0) as usize;
+        let word_count = (frame_count + FRAMES_PER_WORD - 1) / FRAMES_PER_WORD;
+        let bitmap_size = (word_count * 8) as u64;
+
+        let bitmap_start = usable_regions()
+            .find(|r| r.range.end_addr() - r.range.start_addr() >= bitmap_size)
+            .expect("no usable region is large enough to hold the frame bitmap")
+            .range
+            .start_addr();
+        let bitmap_ptr: *mut u64 = (physical_memory_offset + bitmap_start).as_mut_ptr();
+        let bitmap = slice::from_raw_parts_mut(bitmap_ptr, word_count);
+
+        // Start out with every frame marked as used and then free the usable ones.
+        // This way holes in the memory map and non usable regions can never be handed out.
+        for word in bitmap.iter_mut() {
+            *word = !0;
+        }
+
+        let first_bitmap_frame = (bitmap_start / FRAME_SIZE) as usize;
+        let bitmap_frames = ((bitmap_size + FRAME_SIZE - 1) / FRAME_SIZE) as usize;
+        let mut allocator = BitmapFrameAllocator {
+            bitmap,
+            memory_map,
+            bitmap_frames: first_bitmap_frame..first_bitmap_frame + bitmap_frames,
+            next_word: 0,
+            total_frames: 0,
+            free_frames: 0,
+        };
+
+        for region in usable_regions() {
+            for frame_number in region.range.start_frame_number..region.range.end_frame_number {
+                allocator.mark_free(frame_number as usize);
+                allocator.total_frames += 1;
+            }
+        }
+        allocator.free_frames = allocator.total_frames;
+
+        // The frames holding the bitmap are not available for allocation
+        for frame_number in allocator.bitmap_frames.clone() {
+            allocator.mark_used(frame_number);
+            allocator.free_frames -= 1;
+        }
+
+        allocator
+    }
+
+    // Number of usable frames the memory map reported
+    pub fn total_frames(&self) -> usize {
+        self.total_frames
+    }
+
+    // Number of frames that can still be allocated
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    // Number of usable frames that are currently handed out, including the frames that hold the bitmap
+    pub fn used_frames(&self) -> usize {
+        self.total_frames - self.free_frames
+    }
+
+    // Number of frames the bitmap covers, which is one past the highest frame number it can hand out.
+    // Unlike total_frames, this includes the holes and non usable regions below the last usable frame.
+    pub fn frame_count(&self) -> usize {
+        self.bitmap.len() * FRAMES_PER_WORD
+    }
+
+    // Returns whether the frame is one the allocator hands out, as opposed to a frame that was reserved or in use
+    // before the allocator was created, like the page tables of the bootloader
+    pub fn manages(&self, frame: PhysFrame) -> bool {
+        let frame_number = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+        frame_number < self.frame_count() && self.is_usable(frame_number)
+    }
+
+    // Returns whether the given frame is tracked by the bitmap and currently in use
+    fn is_used(&self, frame_number: usize) -> bool {
+        let word = self.bitmap[frame_number / FRAMES_PER_WORD];
+        word & (1 << (frame_number % FRAMES_PER_WORD)) != 0
+    }
+
+    // Returns whether the given frame is usable RAM that the allocator may hand out
+    fn is_usable(&self, frame_number: usize) -> bool {
+        let in_usable_region = self.memory_map.iter().any(|r| {
+            r.region_type == MemoryRegionType::Usable
+                && (r.range.start_frame_number..r.range.end_frame_number)
+                    .contains(&(frame_number as u64))
+        });
+        in_usable_region && !self.bitmap_frames.contains(&frame_number)
+    }
+
+    fn mark_used(&mut self, frame_number: usize) {
+        self.bitmap[frame_number / FRAMES_PER_WORD] |= 1 << (frame_number % FRAMES_PER_WORD);
+    }
+
+    fn mark_free(&mut self, frame_number: usize) {
+        self.bitmap[frame_number / FRAMES_PER_WORD] &= !(1 << (frame_number % FRAMES_PER_WORD));
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        // Look for a word that still has a cleared bit, starting at the search hint and
+        // wrapping around once, since frames before the hint might have been freed again.
+        let word_count = self.bitmap.len();
+        let word_index = (self.next_word..word_count)
+            .chain(0..self.next_word)
+            .find(|&i| self.bitmap[i] != !0)?;
+
+        // The number of trailing ones is the position of the lowest free frame in the word
+        let bit = self.bitmap[word_index].trailing_ones() as usize;
+        let frame_number = word_index * FRAMES_PER_WORD + bit;
+        self.mark_used(frame_number);
+        self.free_frames -= 1;
+        self.next_word = word_index;
+
+        let addr = PhysAddr::new(frame_number as u64 * FRAME_SIZE);
+        Some(PhysFrame::containing_address(addr))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let frame_number = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+        assert!(
+            frame_number < self.bitmap.len() * FRAMES_PER_WORD,
+            "frame {:?} is not managed by the frame allocator",
+            frame
+        );
+        // A set bit doesn't tell a frame that was handed out apart from one that was never usable
+        assert!(
+            self.is_usable(frame_number),
+            "frame {:?} is reserved and was never allocated",
+            frame
+        );
+        // Freeing a frame that is already free means that somebody else still thinks they own it
+        assert!(
+            self.is_used(frame_number),
+            "frame {:?} deallocated twice",
+            frame
+        );
+
+        self.mark_free(frame_number);
+        self.free_frames += 1;
+        // Make sure the next allocation finds the frame we just freed
+        self.next_word = self.next_word.min(frame_number / FRAMES_PER_WORD);
+    }
+}
+
+unsafe impl FrameAllocator<Size2MiB> for BitmapFrameAllocator {
+    // A free 2MiB frame is a run of 8 words that are all zero and that starts at a multiple of 8 words,
+    // so that the frame is aligned to its own size.
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        let first_word = (0..self.bitmap.len() / WORDS_PER_HUGE_FRAME)
+            .map(|i| i * WORDS_PER_HUGE_FRAME)
+            .find(|&w| {
+                self.bitmap[w..w + WORDS_PER_HUGE_FRAME]
+                    .iter()
+                    .all(|&word| word == 0)
+            })?;
+
+        for word in self.bitmap[first_word..first_word + WORDS_PER_HUGE_FRAME].iter_mut() {
+            *word = !0;
+        }
+        self.free_frames -= WORDS_PER_HUGE_FRAME * FRAMES_PER_WORD;
+
+        let addr = PhysAddr::new((first_word * FRAMES_PER_WORD) as u64 * FRAME_SIZE);
+        Some(PhysFrame::containing_address(addr))
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        let start: PhysFrame<Size4KiB> = PhysFrame::containing_address(frame.start_address());
+        for i in 0..(WORDS_PER_HUGE_FRAME * FRAMES_PER_WORD) as u64 {
+            FrameDeallocator::<Size4KiB>::deallocate_frame(self, start + i);
+        }
+    }
+}
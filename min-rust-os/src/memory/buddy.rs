@@ -0,0 +1,288 @@
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::ops::Range;
+use core::slice;
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size2MiB, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+const FRAME_SIZE: u64 = 4096;
+// Blocks of order n are 2^n frames long, so the largest order (9) gives 512 frames or 2MiB
+pub const MAX_ORDER: usize = 9;
+// Marks a frame that is not the first frame of a free block
+const NOT_FREE: u8 = u8::MAX;
+
+// Header that is written into the first frame of every free block. The free lists are
+// doubly linked so that a block can be unlinked in constant time when its buddy is freed.
+struct FreeBlock {
+    prev: Option<usize>,
+    next: Option<usize>,
+}
+
+// Physical frame allocator based on the buddy system. Memory is handed out in naturally aligned blocks of
+// 2^order frames. When a block is freed and its buddy (the other half of the block one order up) is free as
+// well, both are merged back into the larger block. This keeps contiguous runs available for DMA buffers,
+// device rings and 2MiB huge pages.
+pub struct BuddyFrameAllocator {
+    physical_memory_offset: VirtAddr,
+    // One list head per order, each one holding the frame number of the first free block
+    free_lists: [Option<usize>; MAX_ORDER + 1],
+    // For every frame, the order of the free block that starts at it, or NOT_FREE. This is what lets us
+    // check whether the buddy of a freed block is itself free without walking the free lists.
+    block_orders: &'static mut [u8],
+    // Frames that were never handed out can't be freed, so deallocate checks blocks against the usable regions
+    // of the memory map and the frames of the order table
+    memory_map: &'static MemoryMap,
+    table_frames: Range<usize>,
+    total_frames: usize,
+    free_frames: usize,
+}
+
+impl BuddyFrameAllocator {
+    // Create a BuddyFrameAllocator from the passed memory map.
+    // Like the BitmapFrameAllocator, it takes every usable frame of the memory map, so only one of them
+    // can own the physical memory at a time. The block order table is stored in the first usable region that
+    // is large enough to hold it and the free list headers are written into the free frames themselves.
+    // This function is unsafe because the caller must guarantee that the complete physical memory is
+    // mapped at the passed `physical_memory_offset` and that the usable frames aren't used somewhere else.
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let usable_regions = || {
+            memory_map
+                .iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+        };
+
+        // one byte for every frame up to the end of the last usable region
+        let frame_count = usable_regions()
+            .map(|r| r.range.end_frame_number)
+            .max()
+            .unwrap_or(0) as usize;
+        let table_size = frame_count as u64;
+        let table_start = usable_regions()
+            .find(|r| r.range.end_addr() - r.range.start_addr() >= table_size)
+            .expect("no usable region is large enough to hold the buddy order table")
+            .range
+            .start_addr();
+        let table_ptr: *mut u8 = (physical_memory_offset + table_start).as_mut_ptr();
+        let block_orders = slice::from_raw_parts_mut(table_ptr, frame_count);
+        for order in block_orders.iter_mut() {
+            *order = NOT_FREE;
+        }
+
+        let table_end_frame = ((table_start + table_size + FRAME_SIZE - 1) / FRAME_SIZE) as usize;
+        let mut allocator = BuddyFrameAllocator {
+            physical_memory_offset,
+            free_lists: [None; MAX_ORDER + 1],
+            block_orders,
+            memory_map,
+            table_frames: (table_start / FRAME_SIZE) as usize..table_end_frame,
+            total_frames: 0,
+            free_frames: 0,
+        };
+
+        for region in usable_regions() {
+            let mut start = region.range.start_frame_number as usize;
+            let end = region.range.end_frame_number as usize;
+            allocator.total_frames += end - start;
+            // skip the frames that hold the order table
+            if region.range.start_addr() == table_start {
+                start = table_end_frame;
+            }
+
+            // Split the region into the largest naturally aligned blocks that fit in it.
+            // Adjacent regions are merged by free_block as soon as both buddies are free.
+            while start < end {
+                let mut order = MAX_ORDER;
+                while start % (1 << order) != 0 || start + (1 << order) > end {
+                    order -= 1;
+                }
+                allocator.free_block(start, order);
+                allocator.free_frames += 1 << order;
+                start += 1 << order;
+            }
+        }
+
+        allocator
+    }
+
+    // Returns the smallest order whose blocks can hold `size` bytes, if there is one
+    pub fn order_for_size(size: usize) -> Option<usize> {
+        let frames = (size as u64 + FRAME_SIZE - 1) / FRAME_SIZE;
+        (0..=MAX_ORDER).find(|&order| (1 << order) >= frames)
+    }
+
+    // Allocates a block of 2^order physically contiguous frames, aligned to its own size.
+    // Returns the first frame of the block.
+    pub fn allocate(&mut self, order: usize) -> Option<PhysFrame> {
+        assert!(order <= MAX_ORDER, "order {} is too large", order);
+
+        // Take a block from the smallest order that has one available
+        let mut current_order = (order..=MAX_ORDER).find(|&o| self.free_lists[o].is_some())?;
+        let frame_number = self.free_lists[current_order].unwrap();
+        self.remove_block(frame_number, current_order);
+
+        // If the block is larger than requested, split it in half until it has the right size.
+        // The upper half of every split is the buddy of the lower half and goes back onto the free list.
+        while current_order > order {
+            current_order -= 1;
+            self.push_block(frame_number + (1 << current_order), current_order);
+        }
+
+        self.free_frames -= 1 << order;
+        let addr = PhysAddr::new(frame_number as u64 * FRAME_SIZE);
+        Some(PhysFrame::containing_address(addr))
+    }
+
+    // Returns a block of 2^order frames that was handed out by `allocate`.
+    // This function is unsafe because the caller must ensure that the block was allocated
+    // with the same order and that none of its frames are still in use.
+    pub unsafe fn deallocate(&mut self, frame: PhysFrame, order: usize) {
+        let frame_number = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+        assert!(order <= MAX_ORDER, "order {} is too large", order);
+        assert!(
+            frame_number % (1 << order) == 0,
+            "{:?} is not the start of an order {} block",
+            frame,
+            order
+        );
+        assert!(
+            frame_number + (1 << order) <= self.block_orders.len(),
+            "{:?} is not managed by the buddy allocator",
+            frame
+        );
+        assert!(
+            self.is_usable(frame_number, order),
+            "{:?} is reserved and was never allocated",
+            frame
+        );
+        // Freeing a block that is already (partly) free would put the same frames on the free lists twice
+        assert!(
+            !self.overlaps_free_block(frame_number, order),
+            "{:?} is not allocated, it is already free",
+            frame
+        );
+
+        self.free_block(frame_number, order);
+        self.free_frames += 1 << order;
+    }
+
+    // Number of usable frames the memory map reported
+    pub fn total_frames(&self) -> usize {
+        self.total_frames
+    }
+
+    // Number of frames that can still be allocated
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    // Number of usable frames that are currently handed out, including the frames that hold the order table
+    pub fn used_frames(&self) -> usize {
+        self.total_frames - self.free_frames
+    }
+
+    // Returns whether every frame of the block is usable RAM that the allocator may hand out
+    fn is_usable(&self, frame_number: usize, order: usize) -> bool {
+        let mut block = frame_number..frame_number + (1 << order);
+        let overlaps_table =
+            block.start < self.table_frames.end && self.table_frames.start < block.end;
+        // A block can span several adjacent regions, so every frame is checked on its own
+        !overlaps_table
+            && block.all(|frame_number| {
+                self.memory_map.iter().any(|r| {
+                    r.region_type == MemoryRegionType::Usable
+                        && (r.range.start_frame_number..r.range.end_frame_number)
+                            .contains(&(frame_number as u64))
+                })
+            })
+    }
+
+    // Returns whether the block shares a frame with a free block. That free block either starts inside the
+    // block, or it is one of the larger blocks the block is part of.
+    fn overlaps_free_block(&self, frame_number: usize, order: usize) -> bool {
+        let free_inside = self.block_orders[frame_number..frame_number + (1 << order)]
+            .iter()
+            .any(|&o| o != NOT_FREE);
+        let free_around = (order + 1..=MAX_ORDER).any(|o| {
+            let start = frame_number & !((1 << o) - 1);
+            start < self.block_orders.len() && self.block_orders[start] == o as u8
+        });
+        free_inside || free_around
+    }
+
+    // Puts a block back on the free lists, merging it with its buddy for as long as the buddy is free too
+    fn free_block(&mut self, mut frame_number: usize, mut order: usize) {
+        while order < MAX_ORDER {
+            // The buddy of a block only differs from it in the bit that corresponds to the block size
+            let buddy = frame_number ^ (1 << order);
+            if buddy >= self.block_orders.len() || self.block_orders[buddy] != order as u8 {
+                break;
+            }
+            self.remove_block(buddy, order);
+            frame_number = frame_number.min(buddy);
+            order += 1;
+        }
+        self.push_block(frame_number, order);
+    }
+
+    fn block_header(&self, frame_number: usize) -> *mut FreeBlock {
+        let addr = self.physical_memory_offset + frame_number as u64 * FRAME_SIZE;
+        addr.as_mut_ptr()
+    }
+
+    // Adds a free block to the front of the list for its order
+    fn push_block(&mut self, frame_number: usize, order: usize) {
+        let next = self.free_lists[order];
+        unsafe {
+            if let Some(next) = next {
+                (*self.block_header(next)).prev = Some(frame_number);
+            }
+            self.block_header(frame_number)
+                .write(FreeBlock { prev: None, next });
+        }
+        self.free_lists[order] = Some(frame_number);
+        self.block_orders[frame_number] = order as u8;
+    }
+
+    // Unlinks a free block from the list for its order
+    fn remove_block(&mut self, frame_number: usize, order: usize) {
+        let block = unsafe { self.block_header(frame_number).read() };
+        match block.prev {
+            Some(prev) => unsafe { (*self.block_header(prev)).next = block.next },
+            None => self.free_lists[order] = block.next,
+        }
+        if let Some(next) = block.next {
+            unsafe { (*self.block_header(next)).prev = block.prev };
+        }
+        self.block_orders[frame_number] = NOT_FREE;
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        self.allocate(0)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.deallocate(frame, 0)
+    }
+}
+
+// A 2MiB frame is exactly one block of the largest order
+unsafe impl FrameAllocator<Size2MiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        let frame = self.allocate(MAX_ORDER)?;
+        Some(PhysFrame::containing_address(frame.start_address()))
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        self.deallocate(
+            PhysFrame::containing_address(frame.start_address()),
+            MAX_ORDER,
+        )
+    }
+}
@@ -0,0 +1,150 @@
+use super::{
+    physical_memory_offset, refcount, with_active_mapper, without_wx, GlobalFrameAllocator,
+};
+use conquer_once::spin::OnceCell;
+use core::ptr;
+use x86_64::{
+    structures::idt::PageFaultErrorCode,
+    structures::paging::{
+        mapper::{FlagUpdateError, MapToError, MappedFrame, TranslateResult},
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTableFlags,
+        PhysFrame, Size4KiB, Translate,
+    },
+    VirtAddr,
+};
+
+// Marks a read only mapping as copy-on-write. The CPU ignores bits 9 to 11 of a page table entry,
+// so the OS is free to use them.
+pub const COW: PageTableFlags = PageTableFlags::BIT_9;
+
+// A frame full of zeros that any number of pages can share until they are written to
+static ZERO_FRAME: OnceCell<PhysFrame> = OnceCell::uninit();
+
+// Maps `page` to `frame` as a copy-on-write page in the given mapper. The page is mapped read only and marked
+// with the COW flag, and the frame gets another reference. The first write to the page makes the page fault
+// handler give it a private copy of the frame, mapped with `flags`.
+// This function is unsafe because the caller must guarantee that the frame holds data that is meant to be shared,
+// and that every other mapping of the frame is a copy-on-write mapping as well (see make_cow).
+pub unsafe fn map_cow(
+    mapper: &mut impl Mapper<Size4KiB>,
+    page: Page,
+    frame: PhysFrame,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    let cow_flags = (flags - PageTableFlags::WRITABLE) | COW | PageTableFlags::PRESENT;
+    mapper
+        .map_to(page, frame, cow_flags, &mut GlobalFrameAllocator)?
+        .flush();
+    refcount::increment(frame);
+    Ok(())
+}
+
+// Turns an existing mapping of `page` into a copy-on-write mapping and returns its frame, so that the frame
+// can be mapped somewhere else with map_cow. This is how an address space is cloned without copying its memory.
+// This function is unsafe because it changes the permissions of a mapping that others might rely on.
+pub unsafe fn make_cow(
+    mapper: &mut OffsetPageTable,
+    page: Page,
+) -> Result<PhysFrame, FlagUpdateError> {
+    let (frame, flags) = match mapper.translate(page.start_address()) {
+        TranslateResult::Mapped {
+            frame: MappedFrame::Size4KiB(frame),
+            flags,
+            ..
+        } => (frame, flags),
+        _ => return Err(FlagUpdateError::PageNotMapped),
+    };
+
+    // Pages that are already copy-on-write keep their single reference for this mapping
+    if !flags.contains(COW) {
+        let cow_flags = (flags - PageTableFlags::WRITABLE) | COW;
+        mapper.update_flags(page, cow_flags)?.flush();
+        refcount::increment(frame);
+    }
+
+    Ok(frame)
+}
+
+// Maps `page` to the shared zero frame as a copy-on-write page. Reading the page gives zeros, and the first write
+// replaces it with a private zeroed frame. Unlike the frames passed to map_cow, the zero frame always keeps one
+// extra reference for itself, so no page ever ends up owning it.
+pub fn map_zero_page(
+    mapper: &mut impl Mapper<Size4KiB>,
+    page: Page,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    let zero_frame = ZERO_FRAME.get_or_init(|| {
+        let frame = GlobalFrameAllocator
+            .allocate_frame()
+            .expect("failed to allocate the zero frame");
+        unsafe { ptr::write_bytes(frame_ptr(frame), 0, Size4KiB::SIZE as usize) };
+        refcount::increment(frame);
+        frame
+    });
+    unsafe { map_cow(mapper, page, *zero_frame, flags) }
+}
+
+fn frame_ptr(frame: PhysFrame) -> *mut u8 {
+    (physical_memory_offset() + frame.start_address().as_u64()).as_mut_ptr()
+}
+
+// Resolves a write to a copy-on-write page. If other mappings still share the frame, the page gets a copy of
+// it, otherwise it simply becomes writable again. Returns false if the fault has nothing to do with
+// copy-on-write. Like the other fault handlers, this must not allocate on the heap.
+pub fn handle_cow_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    // Copy-on-write pages are present but read only, so only writes that violate the protection are of interest
+    if !error_code
+        .contains(PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE)
+    {
+        return false;
+    }
+
+    // The fault happened in whatever address space is active, which doesn't have to be the kernel's
+    with_active_mapper(|mapper| {
+        let page: Page = Page::containing_address(addr);
+        let (frame, flags) = match mapper.translate(page.start_address()) {
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size4KiB(frame),
+                flags,
+                ..
+            } if flags.contains(COW) => (frame, flags),
+            _ => return false,
+        };
+        let writable_flags = without_wx((flags - COW) | PageTableFlags::WRITABLE);
+
+        if refcount::get(frame) == 1 {
+            // This is the last mapping of the frame, so the page can take it over
+            match unsafe { mapper.update_flags(page, writable_flags) } {
+                Ok(flush) => flush.flush(),
+                Err(_) => return false,
+            }
+            refcount::decrement(frame);
+            return true;
+        }
+
+        let copy = match GlobalFrameAllocator.allocate_frame() {
+            Some(copy) => copy,
+            None => return false,
+        };
+        unsafe {
+            ptr::copy_nonoverlapping(frame_ptr(frame), frame_ptr(copy), Size4KiB::SIZE as usize)
+        };
+
+        // Point the page at the copy. The old mapping doesn't have to be flushed on its own,
+        // because the flush of the new mapping invalidates the same TLB entry.
+        match mapper.unmap(page) {
+            Ok((_, flush)) => flush.ignore(),
+            Err(_) => {
+                unsafe { GlobalFrameAllocator.deallocate_frame(copy) };
+                return false;
+            }
+        }
+        match unsafe { mapper.map_to(page, copy, writable_flags, &mut GlobalFrameAllocator) } {
+            Ok(flush) => flush.flush(),
+            Err(_) => panic!("failed to map the copy of a copy-on-write page"),
+        }
+        refcount::decrement(frame);
+        true
+    })
+    .unwrap_or(false)
+}
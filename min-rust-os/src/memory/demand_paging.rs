@@ -0,0 +1,67 @@
+use super::{
+    physical_memory_offset, swap, vma::KERNEL_VMAS, without_wx, GlobalFrameAllocator, MAPPER,
+};
+use core::ptr;
+use x86_64::{
+    structures::idt::PageFaultErrorCode,
+    structures::paging::{FrameDeallocator, Mapper, Page, PageSize, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+// Backs the page containing `addr` with a zeroed frame if the address lies in a region that was reserved
+// with VmaManager::reserve_on_demand. Returns true if the page was mapped, so that the faulting instruction
+// can be retried.
+// This runs inside the page fault handler, so it must not allocate on the heap, and it only tries to take the
+// locks it needs: if the faulting code already holds one of them, waiting for it would deadlock.
+pub fn map_on_demand(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    // A protection violation means the page is present, so there is nothing to map
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return false;
+    }
+
+    let flags = match KERNEL_VMAS.try_lock().and_then(|vmas| vmas.find(addr)) {
+        Some(vma) => match vma.on_demand {
+            Some(flags) => flags,
+            None => return false,
+        },
+        None => return false,
+    };
+    // Don't bother mapping a page that would fault again straight away
+    if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+        && !flags.contains(PageTableFlags::WRITABLE)
+    {
+        return false;
+    }
+
+    // Allocate before locking the mapper: if memory is short, other pages have to be swapped out first
+    let frame = match swap::allocate_frame() {
+        Some(frame) => frame,
+        None => return false,
+    };
+    // The new page must not leak whatever the frame was used for before
+    let frame_ptr: *mut u8 =
+        (physical_memory_offset() + frame.start_address().as_u64()).as_mut_ptr();
+    unsafe { ptr::write_bytes(frame_ptr, 0, Size4KiB::SIZE as usize) };
+
+    let mut mapper = MAPPER.try_lock();
+    let mapper = match mapper.as_mut().and_then(|mapper| mapper.as_mut()) {
+        Some(mapper) => mapper,
+        None => {
+            unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+            return false;
+        }
+    };
+
+    let page: Page<Size4KiB> = Page::containing_address(addr);
+    let flags = without_wx(flags | PageTableFlags::PRESENT);
+    match unsafe { mapper.map_to(page, frame, flags, &mut GlobalFrameAllocator) } {
+        Ok(flush) => {
+            flush.flush();
+            true
+        }
+        Err(_) => {
+            unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+            false
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use super::{map_fresh_frame, vma::KERNEL_VMAS, FRAME_ALLOCATOR, KERNEL_DATA_FLAGS, MAPPER};
+use conquer_once::spin::OnceCell;
+use core::sync::atomic::{AtomicU16, Ordering};
+use core::{mem, ptr, slice};
+use x86_64::{
+    align_up,
+    structures::paging::{Page, PageSize, PhysFrame, Size4KiB},
+};
+
+// One reference count for every physical frame, indexed by frame number. A count of 0 means that nobody is
+// sharing the frame: it is either free or has a single owner, which is the normal case for frames from the frame
+// allocator. Frames that are mapped more than once (copy-on-write pages, shared memory) track every mapping here.
+// The counts are atomic so that they can be read and updated without a lock, even from the page fault handler.
+static REFCOUNTS: OnceCell<&'static [AtomicU16]> = OnceCell::uninit();
+
+// Sets up the reference count table. The table gets its own region from the VMA manager, which is backed by
+// frames from the global frame allocator, so the kernel mapper and the frame allocator must be set up first.
+pub fn init() {
+    let frame_count = FRAME_ALLOCATOR
+        .lock()
+        .as_ref()
+        .expect("frame allocator not initialised")
+        .frame_count();
+    let size = align_up(
+        (frame_count * mem::size_of::<AtomicU16>()) as u64,
+        Size4KiB::SIZE,
+    );
+    let table = KERNEL_VMAS
+        .lock()
+        .reserve_anywhere("frame refcounts", size, Size4KiB::SIZE)
+        .expect("failed to reserve the frame refcount region");
+
+    {
+        let mut mapper = MAPPER.lock();
+        let mapper = mapper.as_mut().expect("kernel mapper not initialised");
+        let pages = Page::range(
+            Page::containing_address(table.start),
+            Page::containing_address(table.end()),
+        );
+        for page in pages {
+            map_fresh_frame(mapper, page, KERNEL_DATA_FLAGS)
+                .expect("failed to map the frame refcounts");
+        }
+    }
+
+    // Every frame starts out unshared
+    unsafe { ptr::write_bytes(table.start.as_mut_ptr::<u8>(), 0, size as usize) };
+    let counts = unsafe { slice::from_raw_parts(table.start.as_ptr::<AtomicU16>(), frame_count) };
+    REFCOUNTS
+        .try_init_once(|| counts)
+        .expect("frame refcounts should only be initialised once");
+}
+
+fn refcount(frame: PhysFrame) -> &'static AtomicU16 {
+    let counts = REFCOUNTS
+        .try_get()
+        .expect("frame refcounts not initialised");
+    let frame_number = (frame.start_address().as_u64() / Size4KiB::SIZE) as usize;
+    &counts[frame_number]
+}
+
+// Returns the number of references to the given frame
+pub fn get(frame: PhysFrame) -> u16 {
+    refcount(frame).load(Ordering::SeqCst)
+}
+
+// Adds a reference to the given frame and returns the new count
+pub fn increment(frame: PhysFrame) -> u16 {
+    let previous = refcount(frame).fetch_add(1, Ordering::SeqCst);
+    assert!(previous < u16::MAX, "too many references to {:?}", frame);
+    previous + 1
+}
+
+// Drops a reference to the given frame and returns the new count
+pub fn decrement(frame: PhysFrame) -> u16 {
+    let previous = refcount(frame).fetch_sub(1, Ordering::SeqCst);
+    assert!(previous > 0, "{:?} has no references left", frame);
+    previous - 1
+}
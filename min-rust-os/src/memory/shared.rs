@@ -0,0 +1,106 @@
+use super::{physical_memory_offset, refcount, GlobalFrameAllocator};
+use core::ptr;
+use x86_64::structures::paging::{
+    mapper::{MapToError, UnmapError},
+    FrameAllocator, FrameDeallocator, Mapper, Page, PageSize, PageTableFlags, PhysFrame, Size4KiB,
+};
+
+// Marks a mapping of a shared frame. Like the COW flag, it lives in one of the bits the CPU ignores. Unmapping
+// a page with this flag drops the reference of the mapping instead of freeing the frame straight away.
+pub const SHARED: PageTableFlags = PageTableFlags::BIT_11;
+
+// A handle to a physical frame that can be mapped into several address spaces at once, e.g. for shared memory
+// between tasks or for buffers a driver hands to its consumers without copying them. The handle and every
+// mapping made with map each hold a reference in the frame refcount table. The frame goes back to the frame
+// allocator once the last handle is dropped and the last mapping is removed, whichever happens later.
+#[derive(Debug)]
+pub struct SharedFrame {
+    frame: PhysFrame,
+}
+
+impl SharedFrame {
+    // Allocates a zeroed frame from the global frame allocator. Returns `None` if there is no frame left.
+    // The frame refcounts must be initialised (see refcount::init).
+    pub fn new() -> Option<Self> {
+        let frame = GlobalFrameAllocator.allocate_frame()?;
+        unsafe { ptr::write_bytes(frame_ptr(frame), 0, Size4KiB::SIZE as usize) };
+        refcount::increment(frame);
+        Some(SharedFrame { frame })
+    }
+
+    pub fn frame(&self) -> PhysFrame {
+        self.frame
+    }
+
+    // Number of handles and mappings that currently share the frame
+    pub fn ref_count(&self) -> u16 {
+        refcount::get(self.frame)
+    }
+
+    // Pointer to the frame's memory through the physical memory mapping, so the kernel can fill or read the
+    // frame without mapping it anywhere
+    pub fn as_mut_ptr(&self) -> *mut u8 {
+        frame_ptr(self.frame)
+    }
+
+    // Maps `page` to the shared frame in the given mapper, which may belong to any address space. The mapping
+    // gets its own reference to the frame, which is dropped again by unmap, memory::unmap_range or when the
+    // address space the mapping lives in is dropped. Pass the mapper of the active address space (or flush the
+    // TLB yourself), since the new mapping is flushed from the TLB of the current one.
+    // This function is unsafe because every mapping sees the writes of the others, so the caller must make sure
+    // that the users of the frame agree on how it is accessed.
+    pub unsafe fn map(
+        &self,
+        mapper: &mut impl Mapper<Size4KiB>,
+        page: Page,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        let shared_flags = flags | SHARED | PageTableFlags::PRESENT;
+        mapper
+            .map_to(page, self.frame, shared_flags, &mut GlobalFrameAllocator)?
+            .flush();
+        refcount::increment(self.frame);
+        Ok(())
+    }
+}
+
+impl Clone for SharedFrame {
+    fn clone(&self) -> Self {
+        refcount::increment(self.frame);
+        SharedFrame { frame: self.frame }
+    }
+}
+
+impl Drop for SharedFrame {
+    fn drop(&mut self) {
+        release(self.frame);
+    }
+}
+
+// Removes the mapping of a page that was mapped with SharedFrame::map and drops its reference to the frame.
+// The frame is freed if nothing else refers to it anymore. Fails if the page is not mapped, and panics if it
+// is not a shared mapping.
+// This function is unsafe because the caller must guarantee that the page is not used anymore.
+pub unsafe fn unmap(mapper: &mut impl Mapper<Size4KiB>, page: Page) -> Result<(), UnmapError> {
+    let (frame, flush) = mapper.unmap(page)?;
+    flush.flush();
+    assert!(
+        refcount::get(frame) > 0,
+        "{:?} is not mapped to a shared frame",
+        page
+    );
+    release(frame);
+    Ok(())
+}
+
+// Drops a reference to a shared or copy-on-write frame and gives the frame back to the global frame allocator
+// if it was the last
+pub(super) fn release(frame: PhysFrame) {
+    if refcount::decrement(frame) == 0 {
+        unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+    }
+}
+
+fn frame_ptr(frame: PhysFrame) -> *mut u8 {
+    (physical_memory_offset() + frame.start_address().as_u64()).as_mut_ptr()
+}
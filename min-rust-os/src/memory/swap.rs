@@ -0,0 +1,349 @@
+use super::{
+    map_fresh_frame, physical_memory_offset, table_entry, vma::KERNEL_VMAS, without_wx,
+    GlobalFrameAllocator, KERNEL_DATA_FLAGS, MAPPER,
+};
+use alloc::{boxed::Box, vec, vec::Vec};
+use spin::Mutex;
+use x86_64::{
+    align_up,
+    instructions::tlb,
+    structures::idt::PageFaultErrorCode,
+    structures::paging::{
+        page_table::PageTableEntry, FrameAllocator, FrameDeallocator, OffsetPageTable, Page,
+        PageSize, PageTableFlags, PhysFrame, Size2MiB, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+// Marks a non present page table entry whose page was written to a swap slot. The CPU ignores every other bit of
+// a non present entry, so the slot number is stored where the frame address would be.
+pub const SWAPPED: PageTableFlags = PageTableFlags::BIT_10;
+
+// How many pages are evicted when the frame allocator runs dry. Freeing a few at once leaves some frames for the
+// page tables the caller might need in addition to the frame it asked for.
+const EVICT_BATCH: usize = 8;
+
+const PAGE_SIZE: usize = Size4KiB::SIZE as usize;
+
+// A device that can hold swapped out pages, addressed by slot number
+pub trait SwapDevice: Send {
+    fn slot_count(&self) -> usize;
+    fn read(&mut self, slot: usize, page: &mut [u8; PAGE_SIZE]);
+    fn write(&mut self, slot: usize, page: &[u8; PAGE_SIZE]);
+}
+
+// A swap device in RAM. Swapping to RAM doesn't free any memory overall, but it exercises the whole reclaim path
+// until there is a disk driver. The disk gets its own kernel region, which is fully mapped when it is created.
+pub struct RamDisk {
+    start: VirtAddr,
+    slots: usize,
+}
+
+impl RamDisk {
+    // Creates a RAM disk with room for `slots` pages. Returns `None` if there is not enough memory for it.
+    pub fn new(slots: usize) -> Option<Self> {
+        let size = (slots * PAGE_SIZE) as u64;
+        let disk = KERNEL_VMAS
+            .lock()
+            .reserve_anywhere("swap ram disk", size, Size4KiB::SIZE)
+            .ok()?;
+
+        let mut mapper = MAPPER.lock();
+        let mapper = mapper.as_mut()?;
+        let first_page: Page = Page::containing_address(disk.start);
+        for page in Page::range(first_page, first_page + slots as u64) {
+            if map_fresh_frame(mapper, page, KERNEL_DATA_FLAGS).is_err() {
+                let mapped = Page::range(first_page, page);
+                unsafe { super::unmap_range(mapper, mapped, &mut GlobalFrameAllocator) }.ok()?;
+                KERNEL_VMAS.lock().release(disk.start);
+                return None;
+            }
+        }
+
+        Some(RamDisk {
+            start: disk.start,
+            slots,
+        })
+    }
+
+    fn slot_ptr(&self, slot: usize) -> *mut [u8; PAGE_SIZE] {
+        assert!(slot < self.slots, "swap slot {} out of range", slot);
+        (self.start + (slot * PAGE_SIZE) as u64).as_mut_ptr()
+    }
+}
+
+impl SwapDevice for RamDisk {
+    fn slot_count(&self) -> usize {
+        self.slots
+    }
+
+    fn read(&mut self, slot: usize, page: &mut [u8; PAGE_SIZE]) {
+        page.copy_from_slice(unsafe { &*self.slot_ptr(slot) });
+    }
+
+    fn write(&mut self, slot: usize, page: &[u8; PAGE_SIZE]) {
+        unsafe { &mut *self.slot_ptr(slot) }.copy_from_slice(page);
+    }
+}
+
+struct Swap {
+    device: Box<dyn SwapDevice>,
+    // One bit per slot, a set bit means the slot holds a page
+    used: Vec<u64>,
+    used_slots: usize,
+    // The clock hand: the next pageable page eviction looks at
+    hand: VirtAddr,
+}
+
+impl Swap {
+    fn allocate_slot(&mut self) -> Option<usize> {
+        let slot_count = self.device.slot_count();
+        let (index, word) = self
+            .used
+            .iter_mut()
+            .enumerate()
+            .find(|(_, word)| **word != !0)?;
+        let slot = index * 64 + word.trailing_ones() as usize;
+        if slot >= slot_count {
+            return None;
+        }
+        *word |= 1 << (slot % 64);
+        self.used_slots += 1;
+        Some(slot)
+    }
+
+    fn free_slot(&mut self, slot: usize) {
+        let word = &mut self.used[slot / 64];
+        assert!(
+            *word & (1 << (slot % 64)) != 0,
+            "swap slot {} is not in use",
+            slot
+        );
+        *word &= !(1 << (slot % 64));
+        self.used_slots -= 1;
+    }
+}
+
+// Locked after MAPPER and KERNEL_VMAS, see MAPPER for the lock order
+static SWAP: Mutex<Option<Swap>> = Mutex::new(None);
+
+// Sets up swapping to the given device. From then on, pages of on-demand regions (see
+// VmaManager::reserve_on_demand) are pageable: when the frame allocator runs out of frames, the ones that weren't
+// used recently are evicted and swapped back in by the page fault handler on the next access. Frames for
+// on-demand pages, heap growth and kernel stacks are all taken through allocate_frame.
+// The heap must be set up first.
+pub fn init(device: Box<dyn SwapDevice>) {
+    let words = (device.slot_count() + 63) / 64;
+    *SWAP.lock() = Some(Swap {
+        device,
+        used: vec![0; words],
+        used_slots: 0,
+        hand: VirtAddr::zero(),
+    });
+}
+
+// Number of slots of the swap device that hold a page
+pub fn used_slots() -> usize {
+    SWAP.lock().as_ref().map_or(0, |swap| swap.used_slots)
+}
+
+// Allocates a frame from the global frame allocator. If there is none left, pageable pages are evicted to make
+// room. Must not be called while holding the MAPPER lock, since eviction has to change the page tables.
+pub fn allocate_frame() -> Option<PhysFrame> {
+    GlobalFrameAllocator.allocate_frame().or_else(|| {
+        evict(EVICT_BATCH);
+        GlobalFrameAllocator.allocate_frame()
+    })
+}
+
+// Evicts up to `count` pageable pages and returns how many were evicted. The pages are picked with the clock
+// algorithm: the hand sweeps over the pageable pages, and a page that was accessed since the last sweep only
+// loses its ACCESSED flag. A page that wasn't accessed is evicted. Dirty pages are written to the swap device,
+// clean pages were never written since demand paging mapped them as zero pages, so they are simply dropped.
+// Gives up without evicting anything if one of the locks it needs is held, so this is safe to call from the page
+// fault handler.
+pub fn evict(count: usize) -> usize {
+    let mut mapper = match MAPPER.try_lock() {
+        Some(mapper) => mapper,
+        None => return 0,
+    };
+    let mapper = match mapper.as_mut() {
+        Some(mapper) => mapper,
+        None => return 0,
+    };
+    let vmas = match KERNEL_VMAS.try_lock() {
+        Some(vmas) => vmas,
+        None => return 0,
+    };
+    let mut swap = match SWAP.try_lock() {
+        Some(swap) => swap,
+        None => return 0,
+    };
+    let swap = match swap.as_mut() {
+        Some(swap) => swap,
+        None => return 0,
+    };
+
+    // Two full sweeps are enough: the first one clears all ACCESSED flags at worst
+    let pageable_pages: u64 = vmas
+        .iter()
+        .filter(|vma| vma.on_demand.is_some())
+        .map(|vma| vma.size / Size4KiB::SIZE)
+        .sum();
+    let mut budget = 2 * pageable_pages;
+    let offset = physical_memory_offset();
+    let mut evicted = 0;
+
+    while evicted < count && budget > 0 {
+        budget -= 1;
+        // Continue at the hand, or wrap around to the first pageable region
+        let pageable = || vmas.iter().filter(|vma| vma.on_demand.is_some());
+        let addr = match pageable()
+            .filter(|vma| vma.end() > swap.hand)
+            .min_by_key(|vma| vma.start)
+            .or_else(|| pageable().min_by_key(|vma| vma.start))
+        {
+            Some(vma) if vma.contains(swap.hand) => swap.hand,
+            Some(vma) => vma.start,
+            None => break,
+        };
+        swap.hand = addr + Size4KiB::SIZE;
+
+        let entry = match unsafe { table_entry(mapper.level_4_table(), offset, addr, 1) } {
+            Some(entry) => entry,
+            None => {
+                // Nothing in this 2MiB range was ever touched
+                swap.hand = VirtAddr::new(align_up(swap.hand.as_u64(), Size2MiB::SIZE));
+                continue;
+            }
+        };
+        let flags = entry.flags();
+        // Frames that other mappings share can't simply be taken away
+        if !flags.contains(PageTableFlags::PRESENT)
+            || flags.contains(super::cow::COW)
+            || flags.contains(super::shared::SHARED)
+        {
+            continue;
+        }
+        if flags.contains(PageTableFlags::ACCESSED) {
+            entry.set_flags(flags - PageTableFlags::ACCESSED);
+            tlb::flush(addr);
+            continue;
+        }
+
+        let frame = PhysFrame::containing_address(entry.addr());
+        if flags.contains(PageTableFlags::DIRTY) {
+            let slot = match swap.allocate_slot() {
+                Some(slot) => slot,
+                // The swap device is full
+                None => break,
+            };
+            swap.device.write(slot, unsafe { &*frame_ptr(frame) });
+            entry.set_addr(PhysAddr::new(slot as u64 * Size4KiB::SIZE), SWAPPED);
+        } else {
+            entry.set_unused();
+        }
+        tlb::flush(addr);
+        unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+        evicted += 1;
+    }
+
+    evicted
+}
+
+// Reads the page containing `addr` back from the swap device if it was swapped out. Returns true if the page
+// is present again, so that the faulting access can be retried. Like the other fault handlers, this only tries
+// to take the locks it needs and doesn't allocate on the heap.
+pub fn swap_in(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    // Swapped out pages are not present
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return false;
+    }
+    let flags = match KERNEL_VMAS
+        .try_lock()
+        .and_then(|vmas| vmas.find(addr))
+        .and_then(|vma| vma.on_demand)
+    {
+        Some(flags) => flags,
+        None => return false,
+    };
+    let page: Page = Page::containing_address(addr);
+
+    // Check that the page really is swapped out before spending a frame on it
+    let slot = match with_entry(page, |entry| swap_slot(entry)) {
+        Some(Some(slot)) => slot,
+        _ => return false,
+    };
+    // Allocating might evict other pages, so it has to happen while nothing is locked
+    let frame = match allocate_frame() {
+        Some(frame) => frame,
+        None => return false,
+    };
+    // The swap slot is freed, so the page has to be written again when it is evicted the next time.
+    // Marking it dirty makes sure of that.
+    let flags = without_wx(flags | PageTableFlags::PRESENT | PageTableFlags::DIRTY);
+    // The swap state is locked after MAPPER, like everywhere else
+    let swapped_in = with_entry(page, |entry| {
+        let mut swap = SWAP.try_lock()?;
+        let swap = swap
+            .as_mut()
+            .expect("swapped out page without a swap device");
+        if swap_slot(entry) != Some(slot) {
+            return None;
+        }
+        swap.device.read(slot, unsafe { &mut *frame_ptr(frame) });
+        swap.free_slot(slot);
+        entry.set_addr(frame.start_address(), flags);
+        tlb::flush(page.start_address());
+        Some(())
+    });
+
+    if swapped_in.flatten().is_none() {
+        unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+        return false;
+    }
+    true
+}
+
+// Frees the swap slot of `addr` if its page is swapped out, and clears the entry. Used when unmapping a range.
+pub(super) fn discard(mapper: &mut OffsetPageTable, addr: VirtAddr) {
+    let entry = unsafe { table_entry(mapper.level_4_table(), physical_memory_offset(), addr, 1) };
+    if let Some(entry) = entry {
+        discard_entry(entry);
+    }
+}
+
+// Frees the swap slot stored in a level 1 entry if its page is swapped out, and clears the entry
+pub(super) fn discard_entry(entry: &mut PageTableEntry) {
+    if let Some(slot) = swap_slot(entry) {
+        SWAP.lock()
+            .as_mut()
+            .expect("swapped out page without a swap device")
+            .free_slot(slot);
+        entry.set_unused();
+    }
+}
+
+// Returns the swap slot stored in the entry, if it belongs to a swapped out page
+fn swap_slot(entry: &PageTableEntry) -> Option<usize> {
+    let flags = entry.flags();
+    if flags.contains(SWAPPED) && !flags.contains(PageTableFlags::PRESENT) {
+        Some((entry.addr().as_u64() / Size4KiB::SIZE) as usize)
+    } else {
+        None
+    }
+}
+
+// Runs `f` with the level 1 entry of `page` in the kernel page table. Returns `None` if the MAPPER lock is held
+// or there is no level 1 table for the page.
+fn with_entry<R>(page: Page, f: impl FnOnce(&mut PageTableEntry) -> R) -> Option<R> {
+    let mut mapper = MAPPER.try_lock()?;
+    let mapper = mapper.as_mut()?;
+    let offset = physical_memory_offset();
+    let entry = unsafe { table_entry(mapper.level_4_table(), offset, page.start_address(), 1) }?;
+    Some(f(entry))
+}
+
+fn frame_ptr(frame: PhysFrame) -> *mut [u8; PAGE_SIZE] {
+    (physical_memory_offset() + frame.start_address().as_u64()).as_mut_ptr()
+}
@@ -0,0 +1,252 @@
+use crate::random;
+use spin::Mutex;
+use x86_64::{align_up, structures::paging::PageTableFlags, VirtAddr};
+
+// Part of the virtual address space the kernel hands out ranges from. Without randomisation the heap, which is
+// the first region reserved at boot, ends up at the start of the window, `0x_ffff_c000_0000_0000`.
+// The window lies in the upper half, which belongs to the kernel in every address space, and covers 16 level 4
+// entries (8TiB). Their level 3 tables are created at boot (see memory::init_frame_allocator), so every
+// AddressSpace shares them, including the regions that are reserved after the address space was created.
+pub const KERNEL_VMA_START: u64 = 0x_ffff_c000_0000_0000;
+pub const KERNEL_VMA_END: u64 = 0x_ffff_c800_0000_0000;
+// The manager has to work before the heap exists, so the regions are kept in a fixed size array
+const MAX_VMAS: usize = 64;
+// How often a randomised reservation picks a new address after hitting an existing region
+// before it falls back to the lowest free address
+const RANDOM_ATTEMPTS: usize = 16;
+
+// All kernel virtual memory regions. Anything that needs a range of virtual addresses (the heap, stacks,
+// MMIO windows, the framebuffer) reserves it here first, so that two users never map over each other.
+pub static KERNEL_VMAS: Mutex<VmaManager> = Mutex::new(VmaManager::new());
+
+// A named range of reserved virtual memory. Reserving a range doesn't map anything, the owner
+// is responsible for mapping (and unmapping) the pages it needs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Vma {
+    pub name: &'static str,
+    pub start: VirtAddr,
+    pub size: u64,
+    // If set, the region is backed on demand: the first access to an unmapped page of the region
+    // makes the page fault handler map a zeroed frame with these flags.
+    pub on_demand: Option<PageTableFlags>,
+}
+
+impl Vma {
+    // The first address after the region
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end()
+    }
+
+    fn overlaps(&self, start: u64, size: u64) -> bool {
+        start < self.end().as_u64() && self.start.as_u64() < start + size
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaError {
+    // The requested range overlaps the region with the given name
+    Overlap(&'static str),
+    // There is no free range of the requested size left in the kernel window
+    NoSpace,
+    // All slots for regions are in use
+    TooManyRegions,
+}
+
+pub struct VmaManager {
+    vmas: [Option<Vma>; MAX_VMAS],
+    randomize: bool,
+}
+
+impl VmaManager {
+    pub const fn new() -> Self {
+        VmaManager {
+            vmas: [None; MAX_VMAS],
+            randomize: false,
+        }
+    }
+
+    // From now on, place the regions reserved with reserve_anywhere and reserve_on_demand at random addresses
+    // instead of the lowest free one. With the heap, the stacks and the MMIO windows at a different place on every
+    // boot, an exploit can't rely on their addresses, and code that hard-codes an address breaks right away.
+    pub fn enable_randomization(&mut self) {
+        self.randomize = true;
+    }
+
+    // Reserves the range of `size` bytes starting at `start`. This is meant for regions that must live at a
+    // fixed address. Fails if the range overlaps a region that is already reserved.
+    pub fn reserve(
+        &mut self,
+        name: &'static str,
+        start: VirtAddr,
+        size: u64,
+    ) -> Result<Vma, VmaError> {
+        if let Some(vma) = self.iter().find(|v| v.overlaps(start.as_u64(), size)) {
+            return Err(VmaError::Overlap(vma.name));
+        }
+        self.insert(Vma {
+            name,
+            start,
+            size,
+            on_demand: None,
+        })
+    }
+
+    // Reserves `size` bytes anywhere in the kernel window, at an address aligned to `align`.
+    // `align` must be a power of two.
+    pub fn reserve_anywhere(
+        &mut self,
+        name: &'static str,
+        size: u64,
+        align: u64,
+    ) -> Result<Vma, VmaError> {
+        self.reserve_in_window(name, size, align, None)
+    }
+
+    // Like reserve_anywhere, but the pages of the region are only backed by physical memory once they are
+    // touched. This makes it cheap to reserve large regions like heaps and stacks.
+    pub fn reserve_on_demand(
+        &mut self,
+        name: &'static str,
+        size: u64,
+        align: u64,
+        flags: PageTableFlags,
+    ) -> Result<Vma, VmaError> {
+        self.reserve_in_window(name, size, align, Some(flags))
+    }
+
+    fn reserve_in_window(
+        &mut self,
+        name: &'static str,
+        size: u64,
+        align: u64,
+        on_demand: Option<PageTableFlags>,
+    ) -> Result<Vma, VmaError> {
+        if self.randomize {
+            if let Some(start) = self.random_start(size, align) {
+                return self.insert(Vma {
+                    name,
+                    start: VirtAddr::new(start),
+                    size,
+                    on_demand,
+                });
+            }
+        }
+
+        // Try the lowest aligned address first. Whenever the candidate range overlaps a region,
+        // move it past the end of that region and try again.
+        let mut start = align_up(KERNEL_VMA_START, align);
+        loop {
+            if start + size > KERNEL_VMA_END {
+                return Err(VmaError::NoSpace);
+            }
+            match self.iter().find(|v| v.overlaps(start, size)) {
+                Some(vma) => start = align_up(vma.end().as_u64(), align),
+                None => break,
+            }
+        }
+
+        self.insert(Vma {
+            name,
+            start: VirtAddr::new(start),
+            size,
+            on_demand,
+        })
+    }
+
+    // Picks a random aligned start address in the kernel window at which `size` bytes don't overlap any region
+    fn random_start(&self, size: u64, align: u64) -> Option<u64> {
+        let first = align_up(KERNEL_VMA_START, align);
+        if first + size > KERNEL_VMA_END {
+            return None;
+        }
+        let positions = (KERNEL_VMA_END - size - first) / align + 1;
+        (0..RANDOM_ATTEMPTS)
+            .map(|_| first + random::random_u64() % positions * align)
+            .find(|&start| !self.iter().any(|v| v.overlaps(start, size)))
+    }
+
+    // Releases the region that starts at `start` and returns it
+    pub fn release(&mut self, start: VirtAddr) -> Option<Vma> {
+        let slot = self
+            .vmas
+            .iter_mut()
+            .find(|slot| slot.map_or(false, |v| v.start == start))?;
+        slot.take()
+    }
+
+    // Returns the region that contains the given address, if any
+    pub fn find(&self, addr: VirtAddr) -> Option<Vma> {
+        self.iter().find(|v| v.contains(addr))
+    }
+
+    // Returns the first region with the given name, if any
+    pub fn find_by_name(&self, name: &str) -> Option<Vma> {
+        self.iter().find(|v| v.name == name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Vma> + '_ {
+        self.vmas.iter().filter_map(|slot| *slot)
+    }
+
+    fn insert(&mut self, vma: Vma) -> Result<Vma, VmaError> {
+        let slot = self
+            .vmas
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(VmaError::TooManyRegions)?;
+        *slot = Some(vma);
+        Ok(vma)
+    }
+}
+
+#[test_case]
+fn test_reserve_detects_overlaps() {
+    let mut vmas = VmaManager::new();
+    let start = VirtAddr::new(KERNEL_VMA_START);
+    vmas.reserve("first", start, 0x2000).unwrap();
+    assert_eq!(
+        vmas.reserve("second", start + 0x1000u64, 0x2000),
+        Err(VmaError::Overlap("first"))
+    );
+    // directly after the first region is fine
+    vmas.reserve("second", start + 0x2000u64, 0x1000).unwrap();
+    assert_eq!(vmas.find(start + 0x2fffu64).map(|v| v.name), Some("second"));
+    assert_eq!(vmas.find(start + 0x3000u64), None);
+}
+
+#[test_case]
+fn test_reserve_anywhere_skips_used_ranges() {
+    let mut vmas = VmaManager::new();
+    let first = vmas.reserve_anywhere("first", 0x1000, 0x1000).unwrap();
+    assert_eq!(first.start.as_u64(), KERNEL_VMA_START);
+    let second = vmas.reserve_anywhere("second", 0x1000, 0x20_0000).unwrap();
+    assert!(second.start.is_aligned(0x20_0000u64));
+    assert!(second.start >= first.end());
+
+    // released ranges can be handed out again
+    assert_eq!(vmas.release(first.start), Some(first));
+    let third = vmas.reserve_anywhere("third", 0x1000, 0x1000).unwrap();
+    assert_eq!(third.start, first.start);
+    assert_eq!(vmas.find_by_name("first"), None);
+}
+
+#[test_case]
+fn test_randomized_reservations() {
+    let mut vmas = VmaManager::new();
+    vmas.enable_randomization();
+    let first = vmas.reserve_anywhere("first", 0x4000, 0x20_0000).unwrap();
+    let second = vmas.reserve_anywhere("second", 0x4000, 0x1000).unwrap();
+
+    for vma in &[first, second] {
+        assert!(vma.start.as_u64() >= KERNEL_VMA_START);
+        assert!(vma.end().as_u64() <= KERNEL_VMA_END);
+    }
+    assert!(first.start.is_aligned(0x20_0000u64));
+    assert!(!first.overlaps(second.start.as_u64(), second.size));
+    // The chance of hitting the start of the window by accident is negligible
+    assert_ne!(first.start.as_u64(), align_up(KERNEL_VMA_START, 0x20_0000));
+}
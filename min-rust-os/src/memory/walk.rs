@@ -0,0 +1,154 @@
+use core::fmt;
+use x86_64::{
+    structures::paging::{PageTable, PageTableFlags},
+    PhysAddr, VirtAddr,
+};
+
+// The CPU sets these flags on every access, so two neighbouring pages that differ only in them
+// are still shown as a single range
+const IGNORED_FLAGS: PageTableFlags = PageTableFlags::from_bits_truncate(
+    PageTableFlags::ACCESSED.bits() | PageTableFlags::DIRTY.bits(),
+);
+
+// Permissions that only apply if every level of the page table grants them
+const GRANTED_BY_ALL_LEVELS: PageTableFlags = PageTableFlags::from_bits_truncate(
+    PageTableFlags::WRITABLE.bits() | PageTableFlags::USER_ACCESSIBLE.bits(),
+);
+
+// A range of virtual memory that is mapped to a range of physical memory of the same size with the same flags
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    pub start: VirtAddr,
+    pub size: u64,
+    pub phys: PhysAddr,
+    pub flags: PageTableFlags,
+}
+
+impl Mapping {
+    // Whether `next` directly continues this mapping, both in virtual and in physical memory
+    fn is_continued_by(&self, next: &Mapping) -> bool {
+        self.flags == next.flags
+            && self.start.as_u64().checked_add(self.size) == Some(next.start.as_u64())
+            && self.phys + self.size == next.phys
+    }
+}
+
+// Prints the mapping as `virt_start-virt_end -> phys flags`. The end address is inclusive, because the range
+// might end at the very top of the address space.
+impl fmt::Display for Mapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:#018x}-{:#018x} -> {:#x} {:?}",
+            self.start.as_u64(),
+            self.start.as_u64() + (self.size - 1),
+            self.phys.as_u64(),
+            self.flags
+        )
+    }
+}
+
+// Calls `f` for every mapping of the page table hierarchy below `level_4_table`, in ascending order of virtual
+// addresses. Contiguous pages with identical flags are merged into a single mapping. Stops at the first error
+// returned by `f`. The flags of a mapping are the ones that take effect: a page is only writable or user
+// accessible if the entries at every level say so, and it is not executable if any of them has NO_EXECUTE.
+// Nothing is allocated on the heap, so this works before the heap is set up.
+// This function is unsafe because the caller must guarantee that the complete physical memory is mapped to
+// virtual memory at the passed `physical_memory_offset`.
+pub unsafe fn for_each_mapping<E>(
+    level_4_table: &PageTable,
+    physical_memory_offset: VirtAddr,
+    mut f: impl FnMut(Mapping) -> Result<(), E>,
+) -> Result<(), E> {
+    let mut pending = None;
+    walk_table(
+        level_4_table,
+        4,
+        0,
+        GRANTED_BY_ALL_LEVELS,
+        physical_memory_offset,
+        &mut pending,
+        &mut f,
+    )?;
+    match pending {
+        Some(mapping) => f(mapping),
+        None => Ok(()),
+    }
+}
+
+// Writes every mapping below `level_4_table` to `writer`, one per line.
+// This function is unsafe for the same reason as for_each_mapping.
+pub unsafe fn write_mappings(
+    level_4_table: &PageTable,
+    physical_memory_offset: VirtAddr,
+    writer: &mut impl fmt::Write,
+) -> fmt::Result {
+    for_each_mapping(level_4_table, physical_memory_offset, |mapping| {
+        writeln!(writer, "{}", mapping)
+    })
+}
+
+unsafe fn walk_table<E>(
+    table: &PageTable,
+    level: u8,
+    table_start: u64,
+    parent_flags: PageTableFlags,
+    physical_memory_offset: VirtAddr,
+    pending: &mut Option<Mapping>,
+    f: &mut impl FnMut(Mapping) -> Result<(), E>,
+) -> Result<(), E> {
+    // Each entry of a level 1 table covers 4KiB, and every level above covers 512 times as much
+    let entry_size = 4096u64 << (9 * (level as u64 - 1));
+
+    for (index, entry) in table.iter().enumerate() {
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let flags = combine_flags(parent_flags, entry.flags());
+        // Bits 48 to 63 of a virtual address must be copies of bit 47
+        let start = VirtAddr::new_truncate(table_start + index as u64 * entry_size);
+
+        if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+            // In a level 1 entry, bit 7 is the PAT bit, which only selects the cache mode. In a huge page entry,
+            // the PAT bit is bit 12, the lowest address bit, so the address has to be aligned to the page size.
+            let flags = match level {
+                1 => flags - PageTableFlags::HUGE_PAGE,
+                _ => flags,
+            };
+            let mapping = Mapping {
+                start,
+                size: entry_size,
+                phys: PhysAddr::new(entry.addr().as_u64() & !(entry_size - 1)),
+                flags: flags - IGNORED_FLAGS,
+            };
+            match pending {
+                Some(current) if current.is_continued_by(&mapping) => current.size += entry_size,
+                _ => {
+                    if let Some(finished) = pending.replace(mapping) {
+                        f(finished)?;
+                    }
+                }
+            }
+        } else {
+            let next_table_ptr: *const PageTable =
+                (physical_memory_offset + entry.addr().as_u64()).as_ptr();
+            walk_table(
+                &*next_table_ptr,
+                level - 1,
+                start.as_u64(),
+                flags,
+                physical_memory_offset,
+                pending,
+                f,
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+// The flags of `flags` as they take effect below a table entry with `parent_flags`
+fn combine_flags(parent_flags: PageTableFlags, flags: PageTableFlags) -> PageTableFlags {
+    let denied = GRANTED_BY_ALL_LEVELS - parent_flags;
+    (flags - denied) | (parent_flags & PageTableFlags::NO_EXECUTE)
+}
@@ -0,0 +1,70 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::instructions::random::RdRand;
+
+// Where random_u64 gets its numbers from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    // The RDRAND instruction, a hardware random number generator
+    RdRand,
+    // Jitter of the time stamp counter, for CPUs without RDRAND. This is far from cryptographically secure,
+    // but it still differs from boot to boot.
+    TscJitter,
+}
+
+// The previous result of the TSC jitter generator, mixed into the next one so that two calls never return the
+// same value
+static JITTER_STATE: AtomicU64 = AtomicU64::new(0);
+// Set as soon as random_u64 falls back to TSC jitter
+static TSC_JITTER_USED: AtomicBool = AtomicBool::new(false);
+
+// The source of the numbers random_u64 has returned so far. Even with RDRAND, a number comes from TSC jitter when
+// the hardware generator runs dry, and then the numbers are only as good as the weaker source.
+pub fn source() -> Source {
+    if TSC_JITTER_USED.load(Ordering::Relaxed) || RdRand::new().is_none() {
+        Source::TscJitter
+    } else {
+        Source::RdRand
+    }
+}
+
+// Returns a random number, from RDRAND if the CPU supports it and from TSC jitter otherwise
+pub fn random_u64() -> u64 {
+    if let Some(rdrand) = RdRand::new() {
+        // RDRAND fails if the hardware generator runs out of entropy for a moment. Intel recommends retrying
+        // up to 10 times before giving up.
+        for _ in 0..10 {
+            if let Some(value) = rdrand.get_u64() {
+                return value;
+            }
+        }
+    }
+    tsc_jitter()
+}
+
+fn tsc_jitter() -> u64 {
+    TSC_JITTER_USED.store(true, Ordering::Relaxed);
+    let mut state = JITTER_STATE.load(Ordering::Relaxed) ^ rdtsc();
+    // How long a short busy loop takes varies slightly with interrupts, caches and the emulator.
+    // Every measurement only adds a few bits of entropy, so many of them are mixed together.
+    for _ in 0..64 {
+        let start = rdtsc();
+        for _ in 0..(start & 0xff) {
+            core::hint::spin_loop();
+        }
+        state = mix(state ^ rdtsc().wrapping_sub(start));
+    }
+    JITTER_STATE.store(state, Ordering::Relaxed);
+    state
+}
+
+fn rdtsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+// The finaliser of the SplitMix64 generator, which spreads every input bit over the whole output
+fn mix(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
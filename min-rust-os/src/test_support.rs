@@ -0,0 +1,71 @@
+use crate::{allocator, memory};
+use bootloader::BootInfo;
+use core::cell::UnsafeCell;
+use x86_64::VirtAddr;
+
+// Everything the integration tests in tests/ have in common. Each test is a kernel of its own with its own entry
+// point, but the test cases can't take arguments, so whatever they share has to be set up in the entry point and
+// kept in statics.
+
+// Defines the entry point and the panic handler of an integration test. The entry point runs `init`, then the
+// optional setup function with the boot info, and then the test cases. The test must enable the custom test
+// framework with `reexport_test_harness_main = "test_main"`.
+#[macro_export]
+macro_rules! test_entry_point {
+    () => {
+        $crate::test_entry_point!(|_| {});
+    };
+    ($setup:expr) => {
+        ::bootloader::entry_point!(test_kernel_main);
+
+        fn test_kernel_main(boot_info: &'static ::bootloader::BootInfo) -> ! {
+            $crate::init();
+            let setup: fn(&'static ::bootloader::BootInfo) = $setup;
+            setup(boot_info);
+            test_main();
+            $crate::hlt_loop();
+        }
+
+        #[panic_handler]
+        fn panic(info: &::core::panic::PanicInfo) -> ! {
+            $crate::test_panic_handler(info)
+        }
+    };
+}
+
+// Sets up the kernel mapper and the global frame allocator the same way kernel_main does
+pub fn init_memory(boot_info: &'static BootInfo) {
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init_kernel_mapper(phys_mem_offset);
+        memory::init_frame_allocator(&boot_info.memory_map, phys_mem_offset);
+    }
+}
+
+// Like init_memory, and creates the kernel heap afterwards
+pub fn init_memory_and_heap(boot_info: &'static BootInfo) {
+    init_memory(boot_info);
+    allocator::init_heap(
+        memory::MAPPER.lock().as_mut().unwrap(),
+        &mut memory::GlobalFrameAllocator,
+    )
+    .expect("heap initialisation failed");
+}
+
+// Memory for an allocator under test. It is not the kernel heap, so an allocator that manages it can't grow.
+#[repr(align(4096))]
+pub struct TestHeap<const SIZE: usize>(UnsafeCell<[u8; SIZE]>);
+
+// The memory is only ever accessed through the allocator it is handed to
+unsafe impl<const SIZE: usize> Sync for TestHeap<SIZE> {}
+
+impl<const SIZE: usize> TestHeap<SIZE> {
+    pub const fn new() -> Self {
+        TestHeap(UnsafeCell::new([0; SIZE]))
+    }
+
+    // Start address of the memory, to pass to the init function of an allocator together with SIZE
+    pub fn start(&self) -> usize {
+        self.0.get() as usize
+    }
+}
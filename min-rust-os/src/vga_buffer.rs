@@ -3,7 +3,7 @@ use core::fmt::Write;
 use lazy_static::lazy_static;
 use spin::Mutex;
 use volatile::Volatile;
-use x86_64::instructions::interrupts;
+use x86_64::{instructions::interrupts, VirtAddr};
 
 #[allow(dead_code)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -150,6 +150,14 @@ impl Writer {
     }
 }
 
+// Makes the writer use the text buffer at the given address from now on, e.g. a write-combining mapping of
+// the buffer instead of the identity mapping at 0xb8000 the bootloader sets up.
+// This function is unsafe because the caller must guarantee that `addr` maps the VGA text buffer.
+pub unsafe fn set_buffer(addr: VirtAddr) {
+    let buffer: &'static mut Buffer = &mut *addr.as_mut_ptr();
+    interrupts::without_interrupts(move || WRITER.lock().buffer = buffer);
+}
+
 #[allow(dead_code)]
 pub fn print_something() {
     let mut writer = Writer {
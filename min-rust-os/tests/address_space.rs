@@ -0,0 +1,134 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(min_rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use min_rust_os::memory::{self, address_space::AddressSpace, GlobalFrameAllocator};
+use min_rust_os::test_support;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+// An address in the lower half that the kernel doesn't use
+const USER_ADDR: u64 = 0x_1234_0000_0000;
+
+min_rust_os::test_entry_point!(test_support::init_memory);
+
+fn free_frames() -> usize {
+    memory::FRAME_ALLOCATOR
+        .lock()
+        .as_ref()
+        .unwrap()
+        .free_frames()
+}
+
+#[test_case]
+fn mappings_are_private() {
+    let phys_mem_offset = memory::physical_memory_offset();
+    let (kernel_frame, kernel_flags) = Cr3::read();
+    let page = Page::containing_address(VirtAddr::new(USER_ADDR));
+    let frame = GlobalFrameAllocator.allocate_frame().unwrap();
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+    let mut address_space = unsafe { AddressSpace::new(phys_mem_offset) }.unwrap();
+    unsafe {
+        address_space.map_to(page, frame, flags).unwrap();
+        // The kernel mappings are shared, so the code and stack of this test keep working after the switch
+        address_space.activate();
+        assert!(address_space.is_active());
+        let ptr: *mut u64 = page.start_address().as_mut_ptr();
+        ptr.write_volatile(42);
+        Cr3::write(kernel_frame, kernel_flags);
+    }
+
+    // The write went to the mapped frame, while the page stays unmapped in the kernel's address space
+    let frame_ptr: *const u64 = (phys_mem_offset + frame.start_address().as_u64()).as_ptr();
+    assert_eq!(unsafe { frame_ptr.read_volatile() }, 42);
+    assert_eq!(
+        unsafe { memory::translate_addr(page.start_address(), phys_mem_offset) },
+        None
+    );
+
+    drop(address_space);
+    unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+}
+
+#[test_case]
+fn page_tables_are_freed_on_drop() {
+    let phys_mem_offset = memory::physical_memory_offset();
+    let free_before = free_frames();
+    let frame = GlobalFrameAllocator.allocate_frame().unwrap();
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+    let mut address_space = unsafe { AddressSpace::new(phys_mem_offset) }.unwrap();
+    let page = Page::containing_address(VirtAddr::new(USER_ADDR));
+    unsafe { address_space.map_to(page, frame, flags).unwrap() };
+    // level 4, 3, 2 and 1 tables plus the mapped frame
+    assert_eq!(free_frames(), free_before - 5);
+
+    drop(address_space);
+    assert_eq!(free_frames(), free_before - 1);
+    unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+    assert_eq!(free_frames(), free_before);
+}
+
+#[test_case]
+fn kernel_regions_reserved_later_are_shared() {
+    let phys_mem_offset = memory::physical_memory_offset();
+    let (kernel_frame, kernel_flags) = Cr3::read();
+    let address_space = unsafe { AddressSpace::new(phys_mem_offset) }.unwrap();
+
+    // The stack gets its kernel region after the address space was created
+    let stack_top = memory::alloc_kernel_stack(1).unwrap();
+    let ptr: *mut u64 = (stack_top - 8u64).as_mut_ptr();
+    let value = unsafe {
+        ptr.write_volatile(42);
+        address_space.activate();
+        let value = ptr.read_volatile();
+        Cr3::write(kernel_frame, kernel_flags);
+        value
+    };
+    assert_eq!(value, 42);
+
+    unsafe { memory::free_kernel_stack(stack_top) };
+    drop(address_space);
+}
+
+#[test_case]
+fn private_mappings_of_the_active_address_space_are_not_shared() {
+    let phys_mem_offset = memory::physical_memory_offset();
+    let (kernel_frame, kernel_flags) = Cr3::read();
+    let page = Page::containing_address(VirtAddr::new(USER_ADDR));
+    let frame = GlobalFrameAllocator.allocate_frame().unwrap();
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+    let mut first = unsafe { AddressSpace::new(phys_mem_offset) }.unwrap();
+    let second = unsafe {
+        first.map_to(page, frame, flags).unwrap();
+        first.activate();
+        let second = AddressSpace::new(phys_mem_offset);
+        Cr3::write(kernel_frame, kernel_flags);
+        second
+    };
+    let mut second = second.unwrap();
+
+    // mapping the page would panic if its level 4 entry had been shared with the kernel
+    unsafe { second.map_to(page, frame, flags).unwrap() };
+
+    drop(second);
+    drop(first);
+    unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+}
+
+#[test_case]
+fn activate_keeps_the_cr3_flags() {
+    let (kernel_frame, kernel_flags) = Cr3::read();
+    let address_space = unsafe { AddressSpace::new(memory::physical_memory_offset()) }.unwrap();
+    unsafe {
+        address_space.activate();
+        let (_, flags) = Cr3::read();
+        Cr3::write(kernel_frame, kernel_flags);
+        assert_eq!(flags, kernel_flags);
+    }
+}
@@ -0,0 +1,88 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(min_rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::BootInfo;
+use min_rust_os::memory::buddy::{BuddyFrameAllocator, MAX_ORDER};
+use spin::Mutex;
+use x86_64::structures::paging::{FrameAllocator, PhysFrame, Size2MiB, Size4KiB};
+use x86_64::VirtAddr;
+
+static FRAME_ALLOCATOR: Mutex<Option<BuddyFrameAllocator>> = Mutex::new(None);
+
+min_rust_os::test_entry_point!(setup);
+
+fn setup(boot_info: &'static BootInfo) {
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let frame_allocator =
+        unsafe { BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+}
+
+#[test_case]
+fn blocks_are_naturally_aligned() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let frame_allocator = guard.as_mut().unwrap();
+    for order in 0..=MAX_ORDER {
+        let frame = frame_allocator.allocate(order).unwrap();
+        let block_size = 4096u64 << order;
+        assert_eq!(frame.start_address().as_u64() % block_size, 0);
+        unsafe { frame_allocator.deallocate(frame, order) };
+    }
+}
+
+#[test_case]
+fn buddies_are_merged_on_free() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let frame_allocator = guard.as_mut().unwrap();
+    let free_before = frame_allocator.free_frames();
+
+    // Return the largest block one frame at a time. Only if every pair of buddies is merged back
+    // together does the full block end up on the top order list again.
+    let block = frame_allocator.allocate(MAX_ORDER).unwrap();
+    assert_eq!(
+        frame_allocator.free_frames(),
+        free_before - (1 << MAX_ORDER)
+    );
+    for i in 0..(1 << MAX_ORDER) {
+        unsafe { frame_allocator.deallocate(block + i, 0) };
+    }
+    assert_eq!(frame_allocator.free_frames(), free_before);
+
+    let block_again = frame_allocator.allocate(MAX_ORDER).unwrap();
+    assert_eq!(block_again, block);
+    unsafe { frame_allocator.deallocate(block_again, MAX_ORDER) };
+}
+
+#[test_case]
+fn single_and_huge_frames() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let frame_allocator = guard.as_mut().unwrap();
+    let frame: PhysFrame<Size4KiB> = frame_allocator.allocate_frame().unwrap();
+    let huge_frame: PhysFrame<Size2MiB> = frame_allocator.allocate_frame().unwrap();
+    assert_eq!(huge_frame.start_address().as_u64() % (2 * 1024 * 1024), 0);
+    unsafe {
+        frame_allocator.deallocate(frame, 0);
+        frame_allocator.deallocate(
+            PhysFrame::containing_address(huge_frame.start_address()),
+            MAX_ORDER,
+        );
+    }
+}
+
+#[test_case]
+fn order_for_size() {
+    assert_eq!(BuddyFrameAllocator::order_for_size(1), Some(0));
+    assert_eq!(BuddyFrameAllocator::order_for_size(4096), Some(0));
+    assert_eq!(BuddyFrameAllocator::order_for_size(4097), Some(1));
+    assert_eq!(
+        BuddyFrameAllocator::order_for_size(2 * 1024 * 1024),
+        Some(MAX_ORDER)
+    );
+    assert_eq!(
+        BuddyFrameAllocator::order_for_size(2 * 1024 * 1024 + 1),
+        None
+    );
+}
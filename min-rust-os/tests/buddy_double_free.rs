@@ -0,0 +1,38 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use min_rust_os::memory::buddy::BuddyFrameAllocator;
+use min_rust_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("buddy_double_free::freeing_a_free_block_panics...\t");
+    min_rust_os::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut frame_allocator =
+        unsafe { BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+
+    // The first free merges the frame with its buddy again, so the second one hits a frame that is part of a
+    // larger free block
+    let frame = frame_allocator.allocate(0).unwrap();
+    unsafe {
+        frame_allocator.deallocate(frame, 0);
+        frame_allocator.deallocate(frame, 0);
+    }
+
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failure);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
@@ -0,0 +1,134 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(min_rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::BootInfo;
+use min_rust_os::memory::{
+    self, address_space::AddressSpace, cow, refcount, vma::KERNEL_VMAS, GlobalFrameAllocator,
+};
+use min_rust_os::test_support;
+use x86_64::structures::paging::{
+    FrameAllocator, Page, PageTableFlags, PhysFrame, Size4KiB, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+min_rust_os::test_entry_point!(setup);
+
+fn setup(boot_info: &'static BootInfo) {
+    test_support::init_memory(boot_info);
+    refcount::init();
+}
+
+fn translate(addr: VirtAddr) -> PhysAddr {
+    memory::MAPPER
+        .lock()
+        .as_ref()
+        .unwrap()
+        .translate_addr(addr)
+        .unwrap()
+}
+
+// Reserves two pages that are not mapped yet
+fn reserve_pages(name: &'static str) -> (Page, Page) {
+    let vma = KERNEL_VMAS
+        .lock()
+        .reserve_anywhere(name, 2 * 4096, 4096)
+        .unwrap();
+    let first: Page = Page::containing_address(vma.start);
+    (first, first + 1)
+}
+
+#[test_case]
+fn write_copies_shared_frame() {
+    let (first, second) = reserve_pages("cow");
+    let frame: PhysFrame<Size4KiB> = GlobalFrameAllocator.allocate_frame().unwrap();
+    let frame_ptr: *mut u64 =
+        (memory::physical_memory_offset() + frame.start_address().as_u64()).as_mut_ptr();
+    unsafe { frame_ptr.write_volatile(7) };
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    {
+        let mut mapper = memory::MAPPER.lock();
+        let mapper = mapper.as_mut().unwrap();
+        unsafe {
+            cow::map_cow(mapper, first, frame, flags).unwrap();
+            cow::map_cow(mapper, second, frame, flags).unwrap();
+        }
+    }
+    assert_eq!(refcount::get(frame), 2);
+
+    let first_ptr: *mut u64 = first.start_address().as_mut_ptr();
+    let second_ptr: *mut u64 = second.start_address().as_mut_ptr();
+    unsafe {
+        // reading doesn't copy anything
+        assert_eq!(first_ptr.read_volatile(), 7);
+        assert_eq!(second_ptr.read_volatile(), 7);
+        assert_eq!(translate(first.start_address()), frame.start_address());
+
+        // the first write gives the page its own copy
+        first_ptr.write_volatile(42);
+        assert_eq!(first_ptr.read_volatile(), 42);
+        assert_eq!(second_ptr.read_volatile(), 7);
+    }
+    assert_ne!(translate(first.start_address()), frame.start_address());
+    assert_eq!(refcount::get(frame), 1);
+
+    // the last mapping of the frame takes it over instead of copying it
+    unsafe { second_ptr.write_volatile(43) };
+    assert_eq!(translate(second.start_address()), frame.start_address());
+    assert_eq!(refcount::get(frame), 0);
+    unsafe { assert_eq!(first_ptr.read_volatile(), 42) };
+}
+
+#[test_case]
+fn zero_page_is_shared_until_written() {
+    let (first, second) = reserve_pages("cow zero");
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    {
+        let mut mapper = memory::MAPPER.lock();
+        let mapper = mapper.as_mut().unwrap();
+        cow::map_zero_page(mapper, first, flags).unwrap();
+        cow::map_zero_page(mapper, second, flags).unwrap();
+    }
+    let zero_frame = translate(second.start_address());
+    assert_eq!(translate(first.start_address()), zero_frame);
+
+    let first_ptr: *mut u64 = first.start_address().as_mut_ptr();
+    unsafe {
+        assert_eq!(first_ptr.read_volatile(), 0);
+        first_ptr.write_volatile(1);
+    }
+    assert_ne!(translate(first.start_address()), zero_frame);
+    let second_ptr: *const u64 = second.start_address().as_ptr();
+    unsafe { assert_eq!(second_ptr.read_volatile(), 0) };
+}
+
+#[test_case]
+fn dropping_an_address_space_releases_cow_frames() {
+    let free_frames = || {
+        memory::FRAME_ALLOCATOR
+            .lock()
+            .as_ref()
+            .unwrap()
+            .free_frames()
+    };
+    let free_before = free_frames();
+    let frame: PhysFrame<Size4KiB> = GlobalFrameAllocator.allocate_frame().unwrap();
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+    // a lower half address the kernel doesn't use
+    let page: Page = Page::containing_address(VirtAddr::new(0x_1234_0000_0000));
+    let mut address_space = unsafe { AddressSpace::new(memory::physical_memory_offset()) }.unwrap();
+    unsafe {
+        address_space.map_cow(page, frame, flags).unwrap();
+        address_space.map_cow(page + 1, frame, flags).unwrap();
+    }
+    assert_eq!(refcount::get(frame), 2);
+
+    // both mappings go away with the address space, and they held the only references to the frame
+    drop(address_space);
+    assert_eq!(refcount::get(frame), 0);
+    assert_eq!(free_frames(), free_before);
+}
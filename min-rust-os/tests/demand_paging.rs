@@ -0,0 +1,101 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(min_rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use min_rust_os::memory::{self, vma::KERNEL_VMAS};
+use min_rust_os::test_support;
+use x86_64::structures::paging::mapper::TranslateResult;
+use x86_64::structures::paging::{PageTableFlags, Translate};
+use x86_64::VirtAddr;
+
+min_rust_os::test_entry_point!(test_support::init_memory);
+
+fn free_frames() -> usize {
+    memory::FRAME_ALLOCATOR
+        .lock()
+        .as_ref()
+        .unwrap()
+        .free_frames()
+}
+
+fn is_mapped(addr: VirtAddr) -> bool {
+    memory::MAPPER
+        .lock()
+        .as_ref()
+        .unwrap()
+        .translate_addr(addr)
+        .is_some()
+}
+
+#[test_case]
+fn pages_are_mapped_when_touched() {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    // A 1GiB region costs nothing until it is used
+    let vma = KERNEL_VMAS
+        .lock()
+        .reserve_on_demand("demand", 1 << 30, 4096, flags)
+        .unwrap();
+    let addr = vma.start + 0x1234_5000u64;
+    assert!(!is_mapped(addr));
+
+    let ptr: *mut u64 = addr.as_mut_ptr();
+    unsafe {
+        // new pages are zeroed
+        assert_eq!(ptr.read_volatile(), 0);
+        ptr.write_volatile(42);
+        assert_eq!(ptr.read_volatile(), 42);
+    }
+    assert!(is_mapped(addr));
+    // only the touched page was backed
+    assert!(!is_mapped(addr + 4096u64));
+}
+
+#[test_case]
+fn only_one_frame_per_page() {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let vma = KERNEL_VMAS
+        .lock()
+        .reserve_on_demand("demand small", 4096, 4096, flags)
+        .unwrap();
+    let ptr: *mut u8 = vma.start.as_mut_ptr();
+
+    // Touch the page once so that any page tables get allocated, then check that further accesses are free
+    unsafe { ptr.write_volatile(1) };
+    let free_before = free_frames();
+    for i in 0..4096 {
+        unsafe { ptr.add(i).write_volatile(i as u8) };
+    }
+    assert_eq!(free_frames(), free_before);
+}
+
+#[test_case]
+fn scratch_buffers_cost_only_touched_pages() {
+    // Big buffers that are used now and then go into an on-demand region, whose pages are the ones that get
+    // swapped out when memory runs low
+    let vma = KERNEL_VMAS
+        .lock()
+        .reserve_on_demand("scratch", 16 * 1024 * 1024, 4096, memory::KERNEL_DATA_FLAGS)
+        .unwrap();
+    let free_before = free_frames();
+    let ptr: *mut u64 = (vma.start + 8 * 1024 * 1024u64).as_mut_ptr();
+    unsafe {
+        ptr.write_volatile(42);
+        assert_eq!(ptr.read_volatile(), 42);
+    }
+    // the page itself and at most its level 1 and level 2 tables
+    assert!(free_before - free_frames() <= 3);
+
+    let translation = memory::MAPPER
+        .lock()
+        .as_ref()
+        .unwrap()
+        .translate(VirtAddr::from_ptr(ptr));
+    match translation {
+        TranslateResult::Mapped { flags, .. } => {
+            assert!(flags.contains(memory::KERNEL_DATA_FLAGS))
+        }
+        _ => panic!("the scratch page is not mapped"),
+    }
+}
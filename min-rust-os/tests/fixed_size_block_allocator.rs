@@ -0,0 +1,100 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(min_rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::BootInfo;
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr;
+use min_rust_os::allocator::fixed_size_block::FixedSizeBlockAllocator;
+use min_rust_os::allocator::Locked;
+use min_rust_os::test_support::TestHeap;
+
+const HEAP_BYTES: usize = 32 * 1024;
+// 64 byte blocks come in 4KiB pages, the page header takes up the first block
+const BLOCKS: usize = 200;
+const BLOCKS_PER_PAGE: usize = 4096 / 64 - 1;
+
+// The memory the allocator under test hands out. It is not the kernel heap, so the allocator can't grow.
+static HEAP: TestHeap<HEAP_BYTES> = TestHeap::new();
+
+static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
+
+min_rust_os::test_entry_point!(setup);
+
+fn setup(_boot_info: &'static BootInfo) {
+    unsafe { ALLOCATOR.lock().init(HEAP.start(), HEAP_BYTES) };
+}
+
+fn block() -> Layout {
+    Layout::from_size_align(64, 8).unwrap()
+}
+
+fn page_bytes() -> usize {
+    ALLOCATOR.lock().page_bytes()
+}
+
+// Allocates BLOCKS small blocks, then frees them again
+fn burst() {
+    let mut blocks = [ptr::null_mut(); BLOCKS];
+    for ptr in blocks.iter_mut() {
+        *ptr = unsafe { ALLOCATOR.alloc(block()) };
+        assert!(!ptr.is_null());
+    }
+    let pages = (BLOCKS + BLOCKS_PER_PAGE - 1) / BLOCKS_PER_PAGE;
+    assert_eq!(page_bytes(), pages * 4096);
+    for ptr in blocks.iter() {
+        unsafe { ALLOCATOR.dealloc(*ptr, block()) };
+    }
+}
+
+#[test_case]
+fn empty_pages_are_released() {
+    burst();
+    // one empty page is kept for the next allocations
+    assert_eq!(page_bytes(), 4096);
+    assert_eq!(ALLOCATOR.lock().shrink(), 4096);
+    assert_eq!(page_bytes(), 0);
+    assert_eq!(ALLOCATOR.lock().shrink(), 0);
+}
+
+#[test_case]
+fn small_blocks_dont_starve_large_allocations() {
+    burst();
+    // too large to fit next to the empty page that was kept, so the allocator has to release it
+    let layout = Layout::from_size_align(HEAP_BYTES - 2048, 8).unwrap();
+    let large = unsafe { ALLOCATOR.alloc(layout) };
+    assert!(!large.is_null());
+    assert_eq!(page_bytes(), 0);
+    unsafe { ALLOCATOR.dealloc(large, layout) };
+}
+
+#[test_case]
+fn blocks_of_partial_pages_are_used_first() {
+    let first = unsafe { ALLOCATOR.alloc(block()) };
+    let second = unsafe { ALLOCATOR.alloc(block()) };
+    // both come from the same page
+    assert_eq!(first as usize & !4095, second as usize & !4095);
+    assert_eq!(page_bytes(), 4096);
+    unsafe {
+        ALLOCATOR.dealloc(first, block());
+        ALLOCATOR.dealloc(second, block());
+    }
+    assert_eq!(ALLOCATOR.lock().shrink(), 4096);
+}
+
+#[test_case]
+fn largest_blocks_are_not_cut_from_pages() {
+    let layout = Layout::from_size_align(2048, 8).unwrap();
+    let first = unsafe { ALLOCATOR.alloc(layout) };
+    let second = unsafe { ALLOCATOR.alloc(layout) };
+    assert!(!first.is_null() && !second.is_null());
+    assert_eq!(first as usize % 2048, 0);
+    assert_eq!(page_bytes(), 0);
+    unsafe {
+        ALLOCATOR.dealloc(first, layout);
+        ALLOCATOR.dealloc(second, layout);
+    }
+    assert_eq!(ALLOCATOR.lock().shrink(), 0);
+}
@@ -0,0 +1,85 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(min_rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::BootInfo;
+use min_rust_os::memory::bitmap::BitmapFrameAllocator;
+use spin::Mutex;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB};
+use x86_64::VirtAddr;
+
+static FRAME_ALLOCATOR: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
+
+min_rust_os::test_entry_point!(setup);
+
+fn setup(boot_info: &'static BootInfo) {
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+}
+
+#[test_case]
+fn allocate_unique_frames() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let frame_allocator = guard.as_mut().unwrap();
+    let frame_1: PhysFrame = frame_allocator.allocate_frame().unwrap();
+    let frame_2: PhysFrame = frame_allocator.allocate_frame().unwrap();
+    assert_ne!(frame_1, frame_2);
+    unsafe {
+        frame_allocator.deallocate_frame(frame_1);
+        frame_allocator.deallocate_frame(frame_2);
+    }
+}
+
+#[test_case]
+fn counts_follow_allocations() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let frame_allocator = guard.as_mut().unwrap();
+    let free_before = frame_allocator.free_frames();
+    assert_eq!(
+        frame_allocator.free_frames() + frame_allocator.used_frames(),
+        frame_allocator.total_frames()
+    );
+
+    let frame: PhysFrame = frame_allocator.allocate_frame().unwrap();
+    assert_eq!(frame_allocator.free_frames(), free_before - 1);
+
+    unsafe { frame_allocator.deallocate_frame(frame) };
+    assert_eq!(frame_allocator.free_frames(), free_before);
+}
+
+#[test_case]
+fn freed_frame_is_reused() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let frame_allocator = guard.as_mut().unwrap();
+    let frame: PhysFrame = frame_allocator.allocate_frame().unwrap();
+    unsafe { frame_allocator.deallocate_frame(frame) };
+    // The allocator hands out the lowest free frame, so the one we just returned comes back
+    let frame_again: PhysFrame = frame_allocator.allocate_frame().unwrap();
+    assert_eq!(frame, frame_again);
+    unsafe { frame_allocator.deallocate_frame(frame_again) };
+}
+
+#[test_case]
+fn allocate_all_frames() {
+    // Every free frame can be allocated exactly once before the allocator runs dry.
+    // This test leaves the allocator exhausted, so it has to stay the last one in this file.
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let frame_allocator = guard.as_mut().unwrap();
+    let free_before = frame_allocator.free_frames();
+    let first: PhysFrame = frame_allocator.allocate_frame().unwrap();
+    let mut allocated = 1;
+    while let Some(_frame) = FrameAllocator::<Size4KiB>::allocate_frame(frame_allocator) {
+        allocated += 1;
+    }
+    assert_eq!(allocated, free_before);
+    assert_eq!(frame_allocator.free_frames(), 0);
+
+    // Hand one frame back and make sure it is the one we get out again
+    unsafe { frame_allocator.deallocate_frame(first) };
+    let frame_again: Option<PhysFrame> = frame_allocator.allocate_frame();
+    assert_eq!(frame_again, Some(first));
+}
@@ -7,30 +7,10 @@
 extern crate alloc;
 
 use alloc::{boxed::Box, vec::Vec};
-use bootloader::{entry_point, BootInfo};
-use core::panic::PanicInfo;
-use min_rust_os::allocator::{self, HEAP_SIZE};
-use min_rust_os::memory::{self, BootInfoFrameAllocator};
-use x86_64::VirtAddr;
+use min_rust_os::allocator::{self, HEAP_MAX_SIZE, HEAP_SIZE};
+use min_rust_os::test_support;
 
-entry_point!(main);
-
-fn main(boot_info: &'static BootInfo) -> ! {
-    min_rust_os::init();
-
-    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialisation failed");
-
-    test_main();
-    loop {}
-}
-
-#[panic_handler]
-fn panic(info: &PanicInfo) -> ! {
-    min_rust_os::test_panic_handler(info)
-}
+min_rust_os::test_entry_point!(test_support::init_memory_and_heap);
 
 #[test_case]
 fn simple_allocation() {
@@ -54,9 +34,107 @@ fn large_and_multiple_allocations() {
 
 #[test_case]
 fn many_boxes() {
-    // Ensure that the allocator reuses freed memory for subsequent allocations since it would run out of memory otherwise
+    #[cfg(feature = "heap-debug")]
+    allocator::release_quarantine();
+    let before = allocator::stats();
+    // Each box is freed before the next one is allocated, so the allocator can reuse the memory instead of
+    // growing the heap
+    for i in 0..HEAP_SIZE {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+    }
+    // every box was freed again
+    #[cfg(feature = "heap-debug")]
+    allocator::release_quarantine();
+    let after = allocator::stats();
+    assert_eq!(after.bytes_in_use, before.bytes_in_use);
+    assert_eq!(after.allocations, before.allocations + HEAP_SIZE as u64);
+    assert_eq!(after.deallocations, before.deallocations + HEAP_SIZE as u64);
+}
+
+#[test_case]
+fn many_boxes_long_lived() {
+    #[cfg(feature = "heap-track")]
+    let start = allocator::tracking::timestamp();
+    let long_lived = Box::new(1);
     for i in 0..HEAP_SIZE {
         let x = Box::new(i);
         assert_eq!(*x, i);
     }
+    assert_eq!(*long_lived, 1);
+
+    // only the long lived box is left over from the test
+    #[cfg(feature = "heap-track")]
+    {
+        assert_eq!(allocator::tracking::allocated_since(start), 1);
+        drop(long_lived);
+        assert_eq!(allocator::tracking::allocated_since(start), 0);
+    }
+}
+
+#[test_case]
+fn live_allocations_grow_the_heap() {
+    // More than the initial heap is alive at the same time, in allocations that every allocator can serve
+    let mut boxes = Vec::new();
+    for i in 0..HEAP_SIZE / 1024 + 1 {
+        boxes.push(Box::new([i as u8; 1024]));
+    }
+    assert!(allocator::heap_size() > HEAP_SIZE);
+    for (i, block) in boxes.iter().enumerate() {
+        assert!(block.iter().all(|&byte| byte == i as u8));
+    }
+}
+
+// Only the fixed size block allocator has size classes. The red zones of heap-debug change the allocation sizes.
+#[cfg(all(feature = "alloc-fixed-block", not(feature = "heap-debug")))]
+#[test_case]
+fn stats_track_size_classes() {
+    let before = allocator::stats();
+    // a usize fits into the 8 byte blocks, 4KiB is too large for any block size
+    let small = Box::new(1usize);
+    let large: Vec<u8> = Vec::with_capacity(4096);
+    let during = allocator::stats();
+    assert_eq!(during.bytes_in_use, before.bytes_in_use + 8 + 4096);
+    assert!(during.peak_bytes_in_use >= during.bytes_in_use);
+    assert_eq!(
+        during.size_classes[0].allocations,
+        before.size_classes[0].allocations + 1
+    );
+    assert_eq!(
+        during.fallback.bytes_in_use,
+        before.fallback.bytes_in_use + 4096
+    );
+
+    drop(small);
+    drop(large);
+    let after = allocator::stats();
+    assert_eq!(after.bytes_in_use, before.bytes_in_use);
+    assert_eq!(
+        after.fallback.deallocations,
+        before.fallback.deallocations + 1
+    );
+    assert_eq!(after.peak_bytes_in_use, during.peak_bytes_in_use);
+}
+
+#[test_case]
+fn heap_grows_when_full() {
+    assert_eq!(allocator::heap_size() % 4096, 0);
+    // a single allocation larger than the initial heap
+    let mut vec: Vec<u8> = Vec::with_capacity(2 * HEAP_SIZE);
+    vec.resize(2 * HEAP_SIZE, 1);
+    assert!(allocator::heap_size() > 2 * HEAP_SIZE);
+    assert!(allocator::heap_size() <= HEAP_MAX_SIZE);
+    assert_eq!(
+        vec.iter().map(|&b| b as usize).sum::<usize>(),
+        2 * HEAP_SIZE
+    );
+
+    // the memory is reused once it is freed
+    drop(vec);
+    #[cfg(feature = "heap-debug")]
+    allocator::release_quarantine();
+    let size = allocator::heap_size();
+    let vec: Vec<u8> = Vec::with_capacity(2 * HEAP_SIZE);
+    assert_eq!(allocator::heap_size(), size);
+    drop(vec);
 }
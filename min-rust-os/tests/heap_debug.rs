@@ -0,0 +1,111 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(min_rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::BootInfo;
+use core::alloc::{GlobalAlloc, Layout};
+use min_rust_os::allocator::debug::{self, DebugAllocator, POISON, QUARANTINE_BLOCKS};
+use min_rust_os::allocator::fixed_size_block::FixedSizeBlockAllocator;
+use min_rust_os::allocator::Locked;
+use min_rust_os::test_support::TestHeap;
+
+const HEAP_BYTES: usize = 16 * 1024;
+
+// The memory the allocator under test hands out. The tests check the DebugAllocator on its own, so they run
+// with or without the heap-debug feature.
+static HEAP: TestHeap<HEAP_BYTES> = TestHeap::new();
+
+static ALLOCATOR: DebugAllocator<Locked<FixedSizeBlockAllocator>> =
+    DebugAllocator::new(Locked::new(FixedSizeBlockAllocator::new()));
+
+min_rust_os::test_entry_point!(setup);
+
+fn setup(_boot_info: &'static BootInfo) {
+    unsafe { ALLOCATOR.lock().init(HEAP.start(), HEAP_BYTES) };
+}
+
+fn layout() -> Layout {
+    Layout::from_size_align(24, 8).unwrap()
+}
+
+#[test_case]
+fn correct_use_reports_nothing() {
+    let errors = debug::errors();
+    let ptr = unsafe { ALLOCATOR.alloc(layout()) };
+    assert_eq!(ptr as usize % 8, 0);
+    unsafe {
+        ptr.write_bytes(1, 24);
+        ALLOCATOR.dealloc(ptr, layout());
+    }
+    assert_eq!(debug::errors(), errors);
+}
+
+#[test_case]
+fn freed_memory_is_poisoned() {
+    let ptr = unsafe { ALLOCATOR.alloc(layout()) };
+    unsafe {
+        ptr.write_bytes(1, 24);
+        ALLOCATOR.dealloc(ptr, layout());
+        for offset in 0..24 {
+            assert_eq!(ptr.add(offset).read_volatile(), POISON);
+        }
+    }
+}
+
+#[test_case]
+fn overflow_is_detected() {
+    let errors = debug::errors();
+    let ptr = unsafe { ALLOCATOR.alloc(layout()) };
+    unsafe {
+        // one byte too many
+        ptr.write_bytes(1, 25);
+        ALLOCATOR.dealloc(ptr, layout());
+    }
+    assert_eq!(debug::errors(), errors + 1);
+}
+
+#[test_case]
+fn double_free_is_detected() {
+    let errors = debug::errors();
+    let ptr = unsafe { ALLOCATOR.alloc(layout()) };
+    unsafe {
+        ALLOCATOR.dealloc(ptr, layout());
+        ALLOCATOR.dealloc(ptr, layout());
+    }
+    assert_eq!(debug::errors(), errors + 1);
+
+    // the second free didn't reach the block list, so the block is only handed out once
+    let (first, second) = unsafe { (ALLOCATOR.alloc(layout()), ALLOCATOR.alloc(layout())) };
+    assert_ne!(first, second);
+    unsafe {
+        ALLOCATOR.dealloc(first, layout());
+        ALLOCATOR.dealloc(second, layout());
+    }
+    assert_eq!(debug::errors(), errors + 1);
+}
+
+#[test_case]
+fn freed_blocks_are_not_reused_right_away() {
+    let ptr = unsafe { ALLOCATOR.alloc(layout()) };
+    unsafe { ALLOCATOR.dealloc(ptr, layout()) };
+    let again = unsafe { ALLOCATOR.alloc(layout()) };
+    assert_ne!(again, ptr);
+    unsafe { ALLOCATOR.dealloc(again, layout()) };
+}
+
+#[test_case]
+fn write_after_free_is_detected() {
+    let errors = debug::errors();
+    let ptr = unsafe { ALLOCATOR.alloc(layout()) };
+    unsafe {
+        ALLOCATOR.dealloc(ptr, layout());
+        ptr.write_volatile(1);
+    }
+    // the write shows up when the block leaves the quarantine
+    for _ in 0..QUARANTINE_BLOCKS {
+        unsafe { ALLOCATOR.dealloc(ALLOCATOR.alloc(layout()), layout()) };
+    }
+    assert_eq!(debug::errors(), errors + 1);
+}
@@ -0,0 +1,54 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(min_rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::alloc::{alloc, dealloc, Layout};
+use alloc::vec::Vec;
+use bootloader::BootInfo;
+use min_rust_os::allocator::{self, HEAP_SIZE};
+use min_rust_os::memory::{self, GlobalFrameAllocator};
+use min_rust_os::test_support;
+
+// The heap may only grow by a little, so that the limit is reached quickly
+const MAX_SIZE: usize = HEAP_SIZE + 64 * 1024;
+const CHUNK: usize = 16 * 1024;
+
+min_rust_os::test_entry_point!(setup);
+
+fn setup(boot_info: &'static BootInfo) {
+    test_support::init_memory(boot_info);
+    allocator::init_heap_with_max_size(
+        memory::MAPPER.lock().as_mut().unwrap(),
+        &mut GlobalFrameAllocator,
+        MAX_SIZE,
+    )
+    .expect("heap initialisation failed");
+}
+
+#[test_case]
+fn allocation_fails_at_the_limit() {
+    let layout = Layout::from_size_align(CHUNK, 8).unwrap();
+    let mut chunks = Vec::with_capacity(MAX_SIZE / CHUNK);
+    loop {
+        let ptr = unsafe { alloc(layout) };
+        if ptr.is_null() {
+            break;
+        }
+        chunks.push(ptr);
+        assert!(
+            chunks.len() <= MAX_SIZE / CHUNK,
+            "the heap grew past its limit"
+        );
+    }
+    // the heap grew as far as it could before the allocation failed
+    assert!(chunks.len() * CHUNK > HEAP_SIZE);
+    assert_eq!(allocator::heap_size(), MAX_SIZE);
+
+    for ptr in chunks {
+        unsafe { dealloc(ptr, layout) };
+    }
+}
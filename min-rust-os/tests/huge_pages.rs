@@ -0,0 +1,108 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(min_rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::BootInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use min_rust_os::memory::{self, bitmap::BitmapFrameAllocator};
+use spin::Mutex;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTableFlags, Translate,
+};
+use x86_64::VirtAddr;
+
+// 2MiB aligned virtual address that isn't used by anything else
+const HUGE_REGION_START: u64 = 0x_5555_5540_0000;
+const HUGE_REGION_SIZE: u64 = 2 * 1024 * 1024;
+
+static PHYS_MEM_OFFSET: AtomicU64 = AtomicU64::new(0);
+static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+static FRAME_ALLOCATOR: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
+
+min_rust_os::test_entry_point!(setup);
+
+fn setup(boot_info: &'static BootInfo) {
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    PHYS_MEM_OFFSET.store(boot_info.physical_memory_offset, Ordering::Relaxed);
+    *MAPPER.lock() = Some(unsafe { memory::init(phys_mem_offset) });
+    *FRAME_ALLOCATOR.lock() =
+        Some(unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) });
+}
+
+#[test_case]
+fn map_and_translate_huge_range() {
+    let mut mapper = MAPPER.lock();
+    let mapper = mapper.as_mut().unwrap();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let frame_allocator = frame_allocator.as_mut().unwrap();
+
+    let start = VirtAddr::new(HUGE_REGION_START);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    memory::map_huge_range(start, HUGE_REGION_SIZE, flags, mapper, frame_allocator)
+        .expect("mapping huge range failed");
+
+    // The whole range is backed by a single physical frame, so addresses at the beginning
+    // and at the end translate to the same distance apart as their virtual counterparts
+    let phys_mem_offset = VirtAddr::new(PHYS_MEM_OFFSET.load(Ordering::Relaxed));
+    let first = unsafe { memory::translate_addr(start, phys_mem_offset) }.unwrap();
+    let last_virt = start + HUGE_REGION_SIZE - 1u64;
+    let last = unsafe { memory::translate_addr(last_virt, phys_mem_offset) }.unwrap();
+    assert_eq!(first.as_u64() % HUGE_REGION_SIZE, 0);
+    assert_eq!(last - first, HUGE_REGION_SIZE - 1);
+    // The translation agrees with the one from the x86_64 crate
+    assert_eq!(mapper.translate_addr(last_virt), Some(last));
+
+    // The mapping is usable
+    let ptr: *mut u64 = (start + 0x1000u64).as_mut_ptr();
+    unsafe {
+        ptr.write_volatile(42);
+        assert_eq!(ptr.read_volatile(), 42);
+    }
+}
+
+#[test_case]
+fn translate_physical_memory_window() {
+    // Depending on the bootloader, the physical memory window might be mapped with huge pages,
+    // which used to make the translation panic
+    let phys_mem_offset = VirtAddr::new(PHYS_MEM_OFFSET.load(Ordering::Relaxed));
+    let addr = phys_mem_offset + 0x20_1234u64;
+    let phys = unsafe { memory::translate_addr(addr, phys_mem_offset) };
+    assert_eq!(phys.map(|p| p.as_u64()), Some(0x20_1234));
+}
+
+#[test_case]
+fn translate_page_with_pat_bit() {
+    let mut mapper = MAPPER.lock();
+    let mapper = mapper.as_mut().unwrap();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let frame_allocator = frame_allocator.as_mut().unwrap();
+
+    // In a level 1 table, bit 7 is the PAT bit and not the HUGE_PAGE flag. The mapper doesn't let us map a page
+    // with it, so it is set afterwards.
+    let page: Page = Page::containing_address(VirtAddr::new(HUGE_REGION_START + HUGE_REGION_SIZE));
+    let frame = frame_allocator.allocate_frame().unwrap();
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    unsafe {
+        mapper
+            .map_to(page, frame, flags, frame_allocator)
+            .unwrap()
+            .flush();
+        mapper
+            .update_flags(page, flags | PageTableFlags::HUGE_PAGE)
+            .unwrap()
+            .flush();
+    }
+
+    let phys_mem_offset = VirtAddr::new(PHYS_MEM_OFFSET.load(Ordering::Relaxed));
+    let addr = page.start_address() + 0x123u64;
+    let phys = unsafe { memory::translate_addr(addr, phys_mem_offset) };
+    assert_eq!(phys, Some(frame.start_address() + 0x123u64));
+
+    unsafe {
+        mapper.update_flags(page, flags).unwrap().flush();
+        mapper.unmap(page).unwrap().1.flush();
+        frame_allocator.deallocate_frame(frame);
+    }
+}
@@ -0,0 +1,67 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(min_rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use bootloader::BootInfo;
+use min_rust_os::allocator;
+use min_rust_os::memory::{
+    self,
+    vma::{KERNEL_VMAS, KERNEL_VMA_START},
+    CacheMode, GlobalFrameAllocator,
+};
+use min_rust_os::random;
+use min_rust_os::test_support;
+use x86_64::VirtAddr;
+
+min_rust_os::test_entry_point!(setup);
+
+fn setup(boot_info: &'static BootInfo) {
+    test_support::init_memory(boot_info);
+    KERNEL_VMAS.lock().enable_randomization();
+    allocator::init_heap(
+        memory::MAPPER.lock().as_mut().unwrap(),
+        &mut GlobalFrameAllocator,
+    )
+    .expect("heap initialisation failed");
+    memory::log_layout();
+}
+
+#[test_case]
+fn random_numbers_differ() {
+    let first = random::random_u64();
+    let second = random::random_u64();
+    assert_ne!(first, second);
+}
+
+#[test_case]
+fn heap_is_not_at_the_window_start() {
+    let heap = KERNEL_VMAS.lock().find_by_name("heap").unwrap();
+    assert_ne!(heap.start.as_u64(), KERNEL_VMA_START);
+    assert!(heap.start.is_aligned(4096u64));
+
+    // and it still works
+    let value = Box::new(41);
+    assert!(heap.contains(VirtAddr::from_ptr(&*value as *const i32)));
+}
+
+#[test_case]
+fn stacks_and_mmio_windows_are_randomised() {
+    let stack_top = memory::alloc_kernel_stack(1).unwrap();
+    let window =
+        unsafe { memory::map_mmio(x86_64::PhysAddr::new(0xb8000), 4096, CacheMode::Uncached) }
+            .unwrap();
+    assert!(stack_top.as_u64() > KERNEL_VMA_START);
+    assert!(window.as_u64() > KERNEL_VMA_START);
+    // Without randomisation, the two regions would sit right after the heap
+    let heap = KERNEL_VMAS.lock().find_by_name("heap").unwrap();
+    assert_ne!(stack_top - 2 * 4096u64, heap.end());
+    unsafe {
+        memory::unmap_mmio(window);
+        memory::free_kernel_stack(stack_top);
+    }
+}
@@ -0,0 +1,60 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(min_rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use min_rust_os::memory;
+use min_rust_os::test_support;
+use x86_64::structures::paging::Translate;
+use x86_64::VirtAddr;
+
+min_rust_os::test_entry_point!(test_support::init_memory);
+
+fn is_mapped(addr: VirtAddr) -> bool {
+    memory::MAPPER
+        .lock()
+        .as_ref()
+        .unwrap()
+        .translate_addr(addr)
+        .is_some()
+}
+
+#[test_case]
+fn stack_has_guard_page() {
+    let pages = 4;
+    let top = memory::alloc_kernel_stack(pages).unwrap();
+    let bottom = top - pages * 4096;
+
+    assert!(is_mapped(top - 1u64));
+    assert!(is_mapped(bottom));
+    // the page right below the stack stays unmapped
+    assert!(!is_mapped(bottom - 1u64));
+}
+
+#[test_case]
+fn whole_stack_is_usable() {
+    let pages = 2;
+    let top = memory::alloc_kernel_stack(pages).unwrap();
+    let bottom: *mut u64 = (top - pages * 4096).as_mut_ptr();
+    let words = (pages * 4096 / 8) as usize;
+    for i in 0..words {
+        unsafe { bottom.add(i).write_volatile(i as u64) };
+    }
+    for i in 0..words {
+        assert_eq!(unsafe { bottom.add(i).read_volatile() }, i as u64);
+    }
+}
+
+#[test_case]
+fn stacks_do_not_share_guard_pages() {
+    let first = memory::alloc_kernel_stack(1).unwrap();
+    let second = memory::alloc_kernel_stack(1).unwrap();
+    // each stack brings its own guard page, so there is always a gap between two stacks
+    let distance = if first > second {
+        first - second
+    } else {
+        second - first
+    };
+    assert!(distance >= 2 * 4096);
+}
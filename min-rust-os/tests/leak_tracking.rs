@@ -0,0 +1,92 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(min_rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::BootInfo;
+use core::alloc::{GlobalAlloc, Layout};
+use min_rust_os::allocator::fixed_size_block::FixedSizeBlockAllocator;
+use min_rust_os::allocator::tracking::{self, TrackingAllocator};
+use min_rust_os::allocator::Locked;
+use min_rust_os::test_support::TestHeap;
+
+const HEAP_BYTES: usize = 16 * 1024;
+
+// The memory the allocator under test hands out. The tests check the TrackingAllocator on its own, so they run
+// with or without the heap-track feature.
+static HEAP: TestHeap<HEAP_BYTES> = TestHeap::new();
+
+static ALLOCATOR: TrackingAllocator<Locked<FixedSizeBlockAllocator>> =
+    TrackingAllocator::new(Locked::new(FixedSizeBlockAllocator::new()));
+
+min_rust_os::test_entry_point!(setup);
+
+fn setup(_boot_info: &'static BootInfo) {
+    unsafe { ALLOCATOR.lock().init(HEAP.start(), HEAP_BYTES) };
+}
+
+fn layout(size: usize) -> Layout {
+    Layout::from_size_align(size, 8).unwrap()
+}
+
+#[test_case]
+fn freed_allocations_are_forgotten() {
+    let start = tracking::timestamp();
+    unsafe {
+        let ptr = ALLOCATOR.alloc(layout(32));
+        assert_eq!(tracking::allocated_since(start), 1);
+        ALLOCATOR.dealloc(ptr, layout(32));
+    }
+    assert_eq!(tracking::allocated_since(start), 0);
+}
+
+#[test_case]
+fn leaks_are_recorded_with_size_and_caller() {
+    let start = tracking::timestamp();
+    let (a, b, leaked) = unsafe {
+        (
+            ALLOCATOR.alloc(layout(16)),
+            ALLOCATOR.alloc(layout(100)),
+            ALLOCATOR.alloc(layout(40)),
+        )
+    };
+    unsafe {
+        ALLOCATOR.dealloc(a, layout(16));
+        ALLOCATOR.dealloc(b, layout(100));
+    }
+    assert_eq!(tracking::allocated_since(start), 1);
+
+    let mut found = false;
+    tracking::for_each(|record| {
+        if record.addr == leaked as usize {
+            found = true;
+            assert_eq!(record.size, 40);
+            assert!(record.timestamp >= start);
+            // the return address into this test
+            assert_ne!(record.callers[0], 0);
+        }
+    });
+    assert!(found);
+    tracking::dump();
+
+    unsafe { ALLOCATOR.dealloc(leaked, layout(40)) };
+    assert_eq!(tracking::allocated_since(start), 0);
+}
+
+#[test_case]
+fn many_allocations_are_tracked() {
+    let start = tracking::timestamp();
+    let mut ptrs = [core::ptr::null_mut(); 64];
+    for ptr in ptrs.iter_mut() {
+        *ptr = unsafe { ALLOCATOR.alloc(layout(8)) };
+    }
+    assert_eq!(tracking::allocated_since(start), 64);
+    // free them in a different order than they were allocated, so that records are removed from the middle of
+    // probe sequences
+    for ptr in ptrs.iter().step_by(2).chain(ptrs.iter().skip(1).step_by(2)) {
+        unsafe { ALLOCATOR.dealloc(*ptr, layout(8)) };
+    }
+    assert_eq!(tracking::allocated_since(start), 0);
+    assert_eq!(tracking::live(), (0, 0));
+}
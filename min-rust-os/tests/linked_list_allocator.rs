@@ -0,0 +1,113 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(min_rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::BootInfo;
+use core::alloc::{GlobalAlloc, Layout};
+use min_rust_os::allocator::linked_list::{Fragmentation, LinkedListAllocator};
+use min_rust_os::allocator::Locked;
+use min_rust_os::test_support::TestHeap;
+
+const HEAP_BYTES: usize = 4096;
+
+// The memory the allocator under test hands out. It is not the kernel heap, so the allocator can't grow.
+static HEAP: TestHeap<HEAP_BYTES> = TestHeap::new();
+
+static ALLOCATOR: Locked<LinkedListAllocator> = Locked::new(LinkedListAllocator::new());
+
+min_rust_os::test_entry_point!(setup);
+
+fn setup(_boot_info: &'static BootInfo) {
+    unsafe { ALLOCATOR.lock().init(HEAP.start(), HEAP_BYTES) };
+}
+
+fn fragmentation() -> Fragmentation {
+    ALLOCATOR.lock().fragmentation()
+}
+
+#[test_case]
+fn freed_neighbours_are_merged() {
+    let layout = Layout::from_size_align(256, 8).unwrap();
+    let (a, b, c) = unsafe {
+        (
+            ALLOCATOR.alloc(layout),
+            ALLOCATOR.alloc(layout),
+            ALLOCATOR.alloc(layout),
+        )
+    };
+    assert_eq!(fragmentation().free_bytes, HEAP_BYTES - 3 * 256);
+    assert_eq!(fragmentation().free_regions, 1);
+
+    // a hole in front of the used blocks
+    unsafe { ALLOCATOR.dealloc(a, layout) };
+    let free = fragmentation();
+    assert_eq!(free.free_regions, 2);
+    assert_eq!(free.largest_free_region, HEAP_BYTES - 3 * 256);
+    assert_eq!(
+        free.largest_region_percent(),
+        (HEAP_BYTES - 3 * 256) * 100 / (HEAP_BYTES - 2 * 256)
+    );
+
+    // c is merged with the free memory behind it
+    unsafe { ALLOCATOR.dealloc(c, layout) };
+    let free = fragmentation();
+    assert_eq!(free.free_regions, 2);
+    assert_eq!(free.largest_free_region, HEAP_BYTES - 2 * 256);
+
+    // b closes the gap, so everything is one region again
+    unsafe { ALLOCATOR.dealloc(b, layout) };
+    let free = fragmentation();
+    assert_eq!(free.free_regions, 1);
+    assert_eq!(free.free_bytes, HEAP_BYTES);
+    assert_eq!(free.largest_region_percent(), 100);
+}
+
+#[test_case]
+fn large_allocation_succeeds_after_fragmentation() {
+    let layout = Layout::from_size_align(256, 8).unwrap();
+    let mut blocks = [core::ptr::null_mut(); HEAP_BYTES / 256];
+    for block in blocks.iter_mut() {
+        *block = unsafe { ALLOCATOR.alloc(layout) };
+        assert!(!block.is_null());
+    }
+    assert_eq!(fragmentation().free_bytes, 0);
+
+    // free every other block first, which leaves the heap as fragmented as it gets
+    for block in blocks.iter().step_by(2) {
+        unsafe { ALLOCATOR.dealloc(*block, layout) };
+    }
+    assert_eq!(fragmentation().free_regions, blocks.len() / 2);
+    assert_eq!(fragmentation().largest_free_region, 256);
+    for block in blocks.iter().skip(1).step_by(2) {
+        unsafe { ALLOCATOR.dealloc(*block, layout) };
+    }
+
+    // the whole heap is available for a single allocation again
+    let whole = Layout::from_size_align(HEAP_BYTES, 8).unwrap();
+    let ptr = unsafe { ALLOCATOR.alloc(whole) };
+    assert!(!ptr.is_null());
+    unsafe { ALLOCATOR.dealloc(ptr, whole) };
+    assert_eq!(fragmentation().free_regions, 1);
+}
+
+#[test_case]
+fn alignment_gap_stays_free() {
+    let small = Layout::from_size_align(16, 8).unwrap();
+    let aligned = Layout::from_size_align(256, 256).unwrap();
+    // the first block moves the start of the free memory 16 bytes past a 256 byte boundary
+    let first = unsafe { ALLOCATOR.alloc(small) };
+    let second = unsafe { ALLOCATOR.alloc(aligned) };
+    assert_eq!(second as usize % 256, 0);
+    // the 240 bytes in between are still free
+    assert_eq!(fragmentation().free_bytes, HEAP_BYTES - 16 - 256);
+    assert_eq!(fragmentation().free_regions, 2);
+
+    unsafe {
+        ALLOCATOR.dealloc(first, small);
+        ALLOCATOR.dealloc(second, aligned);
+    }
+    assert_eq!(fragmentation().free_bytes, HEAP_BYTES);
+    assert_eq!(fragmentation().free_regions, 1);
+}
@@ -0,0 +1,51 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(min_rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::BootInfo;
+use min_rust_os::memory::{GlobalFrameAllocator, MemoryStats};
+use min_rust_os::test_support;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB};
+
+min_rust_os::test_entry_point!(setup);
+
+fn setup(boot_info: &'static BootInfo) {
+    // Nothing is known before the frame allocator is set up
+    assert_eq!(MemoryStats::current(), None);
+
+    test_support::init_memory(boot_info);
+}
+
+#[test_case]
+fn categories_add_up() {
+    let stats = MemoryStats::current().unwrap();
+    // reserved memory doesn't count towards the total
+    assert_eq!(
+        stats.usable + stats.kernel + stats.page_tables + stats.bootloader,
+        stats.total
+    );
+    assert!(stats.usable > 0);
+    assert!(stats.kernel > 0);
+    assert!(stats.page_tables > 0);
+    // every usable frame is either allocated or free
+    assert_eq!(
+        (stats.allocated_frames + stats.free_frames) as u64 * 4096,
+        stats.usable
+    );
+}
+
+#[test_case]
+fn frames_are_tracked_at_runtime() {
+    let before = MemoryStats::current().unwrap();
+    let frame: PhysFrame<Size4KiB> = GlobalFrameAllocator.allocate_frame().unwrap();
+    let during = MemoryStats::current().unwrap();
+    assert_eq!(during.allocated_frames, before.allocated_frames + 1);
+    assert_eq!(during.free_frames, before.free_frames - 1);
+    // the memory map itself doesn't change
+    assert_eq!(during.usable, before.usable);
+
+    unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+    assert_eq!(MemoryStats::current().unwrap(), before);
+}
@@ -0,0 +1,104 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(min_rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use min_rust_os::memory::{self, vma::KERNEL_VMAS, CacheMode};
+use min_rust_os::test_support;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::mapper::TranslateResult;
+use x86_64::structures::paging::{PageTableFlags, Translate};
+use x86_64::{PhysAddr, VirtAddr};
+
+min_rust_os::test_entry_point!(test_support::init_memory);
+
+fn translate(addr: VirtAddr) -> TranslateResult {
+    memory::MAPPER.lock().as_ref().unwrap().translate(addr)
+}
+
+// The VGA text buffer is the one device every PC has
+const VGA_BUFFER: u64 = 0xb8000;
+
+#[test_case]
+fn mmio_pages_are_uncached() {
+    let addr =
+        unsafe { memory::map_mmio(PhysAddr::new(VGA_BUFFER), 4000, CacheMode::Uncached) }.unwrap();
+    match translate(addr) {
+        TranslateResult::Mapped { frame, flags, .. } => {
+            assert_eq!(frame.start_address().as_u64(), VGA_BUFFER);
+            assert!(flags.contains(PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH));
+        }
+        _ => panic!("MMIO window not mapped"),
+    }
+
+    // writes go straight to the device
+    let ptr: *mut u16 = addr.as_mut_ptr();
+    let vga_ptr: *const u16 = (memory::physical_memory_offset() + VGA_BUFFER).as_ptr();
+    unsafe {
+        ptr.write_volatile(0x0f41);
+        assert_eq!(vga_ptr.read_volatile(), 0x0f41);
+    }
+
+    unsafe { memory::unmap_mmio(addr) };
+}
+
+#[test_case]
+fn unaligned_ranges_cover_all_pages() {
+    // 0x10 bytes before the end of the first page, 0x20 bytes long, so two pages are needed
+    let phys = PhysAddr::new(VGA_BUFFER + 0xff0);
+    let addr = unsafe { memory::map_mmio(phys, 0x20, CacheMode::Uncached) }.unwrap();
+    assert_eq!(addr.as_u64() % 4096, 0xff0);
+    let window = KERNEL_VMAS.lock().find(addr).unwrap();
+    assert_eq!(window.size, 2 * 4096);
+    assert!(matches!(
+        translate(addr + 0x1fu64),
+        TranslateResult::Mapped { .. }
+    ));
+
+    unsafe { memory::unmap_mmio(addr + 0x10u64) };
+    assert!(matches!(translate(addr), TranslateResult::NotMapped));
+    assert!(matches!(
+        translate(addr + 0x10u64),
+        TranslateResult::NotMapped
+    ));
+    assert_eq!(KERNEL_VMAS.lock().find(addr), None);
+}
+
+#[test_case]
+fn pat_is_programmed() {
+    // the power on values, except for entry 1, which is write-combining instead of write-through
+    let pat = unsafe { Msr::new(0x277).read() };
+    assert_eq!(pat, 0x0007_0406_0007_0106);
+}
+
+#[test_case]
+fn write_combining_selects_pat_entry_1() {
+    let addr =
+        unsafe { memory::map_mmio(PhysAddr::new(VGA_BUFFER), 4000, CacheMode::WriteCombining) }
+            .unwrap();
+    match translate(addr) {
+        TranslateResult::Mapped { flags, .. } => {
+            assert!(flags.contains(PageTableFlags::WRITE_THROUGH));
+            assert!(!flags.contains(PageTableFlags::NO_CACHE));
+        }
+        _ => panic!("MMIO window not mapped"),
+    }
+    unsafe { memory::unmap_mmio(addr) };
+}
+
+#[test_case]
+fn write_through_selects_pat_entry_5() {
+    let addr =
+        unsafe { memory::map_mmio(PhysAddr::new(VGA_BUFFER), 4000, CacheMode::WriteThrough) }
+            .unwrap();
+    match translate(addr) {
+        TranslateResult::Mapped { flags, .. } => {
+            assert!(flags.contains(memory::PAT_4KIB | PageTableFlags::WRITE_THROUGH));
+            assert!(!flags.contains(PageTableFlags::NO_CACHE));
+        }
+        _ => panic!("MMIO window not mapped"),
+    }
+    unsafe { memory::unmap_mmio(addr) };
+    assert!(matches!(translate(addr), TranslateResult::NotMapped));
+}
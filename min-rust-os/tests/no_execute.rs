@@ -0,0 +1,95 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(min_rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use min_rust_os::memory::{self, vma::KERNEL_VMAS, walk, GlobalFrameAllocator};
+use min_rust_os::test_support;
+use x86_64::registers::control::Cr3;
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::mapper::TranslateResult;
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, Page, PageTable, PageTableFlags, Translate,
+};
+use x86_64::VirtAddr;
+
+min_rust_os::test_entry_point!(test_support::init_memory_and_heap);
+
+fn flags_of(addr: VirtAddr) -> PageTableFlags {
+    match memory::MAPPER.lock().as_ref().unwrap().translate(addr) {
+        TranslateResult::Mapped { flags, .. } => flags,
+        _ => panic!("{:?} is not mapped", addr),
+    }
+}
+
+#[test_case]
+fn nx_is_enabled() {
+    assert!(Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE));
+}
+
+#[test_case]
+fn heap_and_stacks_are_not_executable() {
+    let heap_value = Box::new(41);
+    let heap_addr = VirtAddr::from_ptr(&*heap_value as *const i32);
+    assert!(flags_of(heap_addr).contains(PageTableFlags::NO_EXECUTE));
+
+    let stack_top = memory::alloc_kernel_stack(1).unwrap();
+    assert!(flags_of(stack_top - 1u64).contains(PageTableFlags::NO_EXECUTE));
+}
+
+#[test_case]
+fn boot_mappings_pass_the_audit() {
+    // the bootloader's stack and boot information were writable and executable before init_frame_allocator
+    let on_stack = 0u8;
+    let stack_addr = VirtAddr::from_ptr(&on_stack);
+    assert!(flags_of(stack_addr).contains(PageTableFlags::NO_EXECUTE));
+    assert_eq!(memory::audit_wx(), 0);
+}
+
+#[test_case]
+fn audit_finds_writable_code() {
+    let before = memory::audit_wx();
+
+    let vma = KERNEL_VMAS
+        .lock()
+        .reserve_anywhere("wx", 4096, 4096)
+        .unwrap();
+    let page: Page = Page::containing_address(vma.start);
+    let frame = GlobalFrameAllocator.allocate_frame().unwrap();
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    unsafe {
+        memory::MAPPER
+            .lock()
+            .as_mut()
+            .unwrap()
+            .map_to(page, frame, flags, &mut GlobalFrameAllocator)
+            .unwrap()
+            .flush()
+    };
+
+    assert_eq!(memory::audit_wx(), before + 1);
+}
+
+#[test_case]
+fn physical_memory_window_is_not_executable() {
+    let offset = memory::physical_memory_offset();
+    let (level_4_frame, _) = Cr3::read();
+    let level_4_table: *const PageTable =
+        (offset + level_4_frame.start_address().as_u64()).as_ptr();
+    let mut window_mappings = 0;
+    unsafe {
+        walk::for_each_mapping(&*level_4_table, offset, |mapping| {
+            if mapping.start.p4_index() == offset.p4_index() {
+                assert!(mapping.flags.contains(PageTableFlags::NO_EXECUTE));
+                window_mappings += 1;
+            }
+            Ok::<(), ()>(())
+        })
+        .unwrap();
+    }
+    assert!(window_mappings > 0);
+}
@@ -0,0 +1,91 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(min_rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, vec::Vec};
+use bootloader::BootInfo;
+use min_rust_os::allocator::{self, HEAP_SIZE};
+use min_rust_os::memory::{
+    self,
+    swap::{self, RamDisk},
+    vma::KERNEL_VMAS,
+    GlobalFrameAllocator,
+};
+use min_rust_os::{serial_print, test_support};
+use x86_64::structures::paging::{Page, PageTableFlags};
+
+// Unlike tests/swap.rs, this test doesn't hide frames from the kernel, it touches more pages than the machine
+// has memory for. The bootimage runner gives every test the same amount of memory, so the test-overcommit alias
+// in .cargo/config.toml passes a smaller one to QEMU. With more memory than MAX_MEMORY, the test is skipped.
+
+min_rust_os::test_entry_point!(setup);
+
+fn setup(boot_info: &'static BootInfo) {
+    test_support::init_memory_and_heap(boot_info);
+    swap::init(Box::new(RamDisk::new(SWAP_SLOTS).unwrap()));
+}
+
+const SWAP_SLOTS: usize = 512;
+const MAX_MEMORY: usize = 64 * 1024 * 1024;
+
+fn free_frames() -> usize {
+    memory::FRAME_ALLOCATOR
+        .lock()
+        .as_ref()
+        .unwrap()
+        .free_frames()
+}
+
+#[test_case]
+fn heap_and_stacks_grow_by_swapping() {
+    let total_frames = memory::FRAME_ALLOCATOR
+        .lock()
+        .as_ref()
+        .unwrap()
+        .total_frames();
+    if total_frames * 4096 > MAX_MEMORY {
+        serial_print!("[skipped, run `cargo test-overcommit`] ");
+        return;
+    }
+
+    // A quarter of the swap slots more than there are free frames
+    let pages = (free_frames() + SWAP_SLOTS / 4) as u64;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let vma = KERNEL_VMAS
+        .lock()
+        .reserve_on_demand("overcommit", pages * 4096, 4096, flags)
+        .unwrap();
+    let page_ptr = |i: u64| -> *mut u64 { (vma.start + i * 4096).as_mut_ptr() };
+
+    for i in 0..pages {
+        unsafe { page_ptr(i).write_volatile(i * 3 + 1) };
+    }
+    assert!(swap::used_slots() > 0);
+    assert!(free_frames() < 16);
+
+    // No frame is free, so growing the heap and allocating a stack take frames from the region
+    let heap_size = allocator::heap_size();
+    let mut vec: Vec<u8> = Vec::with_capacity(2 * HEAP_SIZE);
+    vec.resize(2 * HEAP_SIZE, 1);
+    assert!(allocator::heap_size() > heap_size);
+    let stack_top = memory::alloc_kernel_stack(4).expect("no frames for the kernel stack");
+
+    for i in 0..pages {
+        assert_eq!(unsafe { page_ptr(i).read_volatile() }, i * 3 + 1);
+    }
+    assert!(vec.iter().all(|&byte| byte == 1));
+
+    unsafe { memory::free_kernel_stack(stack_top) };
+    let mut mapper = memory::MAPPER.lock();
+    let region = Page::range(
+        Page::containing_address(vma.start),
+        Page::containing_address(vma.end()),
+    );
+    unsafe { memory::unmap_range(mapper.as_mut().unwrap(), region, &mut GlobalFrameAllocator) }
+        .unwrap();
+    assert_eq!(swap::used_slots(), 0);
+}
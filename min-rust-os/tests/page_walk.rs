@@ -0,0 +1,195 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(min_rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::fmt::{self, Write};
+use min_rust_os::memory::{self, vma::KERNEL_VMAS, walk::Mapping};
+use min_rust_os::test_support;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{Mapper, Page, PageTable, PageTableFlags, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+
+min_rust_os::test_entry_point!(test_support::init_memory);
+
+// A line of text on the stack, since there is no heap
+struct Line {
+    bytes: [u8; 128],
+    len: usize,
+}
+
+impl Line {
+    fn new() -> Self {
+        Line {
+            bytes: [0; 128],
+            len: 0,
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap()
+    }
+}
+
+impl Write for Line {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        if end > self.bytes.len() {
+            return Err(fmt::Error);
+        }
+        self.bytes[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+// Looks for a line in the output of dump_mappings, keeping only the current line
+struct LineFinder<'a> {
+    expected: &'a str,
+    line: Line,
+    found: bool,
+}
+
+impl Write for LineFinder<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for part in s.split_inclusive('\n') {
+            // lines longer than the buffer can't be the expected one anyway
+            let _ = self.line.write_str(part.trim_end_matches('\n'));
+            if part.ends_with('\n') {
+                self.found |= self.line.as_str() == self.expected;
+                self.line.len = 0;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Maps three pages to the VGA buffer and the two frames after it. The first two pages share their flags.
+fn map_test_pages(name: &'static str) -> VirtAddr {
+    let vma = KERNEL_VMAS
+        .lock()
+        .reserve_anywhere(name, 3 * 4096, 4096)
+        .unwrap();
+    let first: Page = Page::containing_address(vma.start);
+    let writable = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let mut mapper = memory::MAPPER.lock();
+    let mapper = mapper.as_mut().unwrap();
+    for (i, flags) in [writable, writable, PageTableFlags::PRESENT]
+        .iter()
+        .enumerate()
+    {
+        let frame = PhysFrame::containing_address(PhysAddr::new(0xb8000 + i as u64 * 4096));
+        unsafe {
+            mapper
+                .map_to(
+                    first + i as u64,
+                    frame,
+                    *flags,
+                    &mut memory::GlobalFrameAllocator,
+                )
+                .unwrap()
+                .flush()
+        };
+    }
+    vma.start
+}
+
+fn mappings_at(start: VirtAddr, found: &mut [Option<Mapping>; 2]) {
+    let (level_4_frame, _) = Cr3::read();
+    let offset = memory::physical_memory_offset();
+    let level_4_table: *const PageTable =
+        (offset + level_4_frame.start_address().as_u64()).as_ptr();
+    let end = start + 3 * 4096u64;
+    let mut count = 0;
+    unsafe {
+        memory::walk::for_each_mapping(&*level_4_table, offset, |mapping| {
+            if mapping.start >= start && mapping.start < end {
+                found[count] = Some(mapping);
+                count += 1;
+            }
+            Ok::<(), ()>(())
+        })
+        .unwrap();
+    }
+}
+
+#[test_case]
+fn contiguous_pages_are_merged() {
+    let start = map_test_pages("walk merge");
+    let mut found = [None; 2];
+    mappings_at(start, &mut found);
+
+    assert_eq!(
+        found[0],
+        Some(Mapping {
+            start,
+            size: 2 * 4096,
+            phys: PhysAddr::new(0xb8000),
+            flags: PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+        })
+    );
+    // different flags start a new range
+    assert_eq!(
+        found[1],
+        Some(Mapping {
+            start: start + 2 * 4096u64,
+            size: 4096,
+            phys: PhysAddr::new(0xba000),
+            flags: PageTableFlags::PRESENT,
+        })
+    );
+}
+
+#[test_case]
+fn dump_prints_ranges() {
+    let start = map_test_pages("walk dump");
+    let mut expected = Line::new();
+    write!(
+        expected,
+        "{:#018x}-{:#018x} -> 0xb8000 PRESENT | WRITABLE",
+        start.as_u64(),
+        start.as_u64() + 2 * 4096 - 1
+    )
+    .unwrap();
+
+    let mut finder = LineFinder {
+        expected: expected.as_str(),
+        line: Line::new(),
+        found: false,
+    };
+    memory::dump_mappings(&mut finder).unwrap();
+    assert!(finder.found);
+}
+
+#[test_case]
+fn pat_bit_is_not_a_huge_page() {
+    let start = map_test_pages("walk pat");
+    let first: Page = Page::containing_address(start);
+    let writable = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    {
+        let mut mapper = memory::MAPPER.lock();
+        let mapper = mapper.as_mut().unwrap();
+        for page in Page::range(first, first + 2) {
+            unsafe {
+                mapper
+                    .update_flags(page, writable | memory::PAT_4KIB)
+                    .unwrap()
+                    .flush()
+            };
+        }
+    }
+    let mut found = [None; 2];
+    mappings_at(start, &mut found);
+
+    // the pages are still 4KiB pages, and the walk found the level 1 entries
+    assert_eq!(
+        found[0],
+        Some(Mapping {
+            start,
+            size: 2 * 4096,
+            phys: PhysAddr::new(0xb8000),
+            flags: writable,
+        })
+    );
+}
@@ -0,0 +1,146 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(min_rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::BootInfo;
+use min_rust_os::memory::{
+    self, address_space::AddressSpace, refcount, shared::SharedFrame, vma::KERNEL_VMAS,
+    GlobalFrameAllocator,
+};
+use min_rust_os::test_support;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+// An address in the lower half that the kernel doesn't use
+const USER_ADDR: u64 = 0x_1234_0000_0000;
+
+min_rust_os::test_entry_point!(setup);
+
+fn setup(boot_info: &'static BootInfo) {
+    test_support::init_memory(boot_info);
+    refcount::init();
+}
+
+fn free_frames() -> usize {
+    memory::FRAME_ALLOCATOR
+        .lock()
+        .as_ref()
+        .unwrap()
+        .free_frames()
+}
+
+#[test_case]
+fn handles_share_one_frame() {
+    let free_before = free_frames();
+    let frame = SharedFrame::new().unwrap();
+    let clone = frame.clone();
+    assert_eq!(clone.frame(), frame.frame());
+    assert_eq!(frame.ref_count(), 2);
+
+    unsafe { frame.as_mut_ptr().write_volatile(7) };
+    assert_eq!(unsafe { clone.as_mut_ptr().read_volatile() }, 7);
+
+    drop(frame);
+    assert_eq!(clone.ref_count(), 1);
+    assert_eq!(free_frames(), free_before - 1);
+    drop(clone);
+    assert_eq!(free_frames(), free_before);
+}
+
+#[test_case]
+fn frame_is_shared_between_address_spaces() {
+    let phys_mem_offset = memory::physical_memory_offset();
+    let (kernel_frame, kernel_flags) = Cr3::read();
+    let free_before = free_frames();
+    let page = Page::containing_address(VirtAddr::new(USER_ADDR));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+    let frame = SharedFrame::new().unwrap();
+    let mut first = unsafe { AddressSpace::new(phys_mem_offset) }.unwrap();
+    let mut second = unsafe { AddressSpace::new(phys_mem_offset) }.unwrap();
+    unsafe {
+        first.map_shared(page, &frame, flags).unwrap();
+        second.map_shared(page, &frame, flags).unwrap();
+    }
+    assert_eq!(frame.ref_count(), 3);
+
+    // a write in one address space shows up in the other
+    let ptr: *mut u64 = page.start_address().as_mut_ptr();
+    unsafe {
+        first.activate();
+        ptr.write_volatile(42);
+        second.activate();
+        assert_eq!(ptr.read_volatile(), 42);
+        Cr3::write(kernel_frame, kernel_flags);
+    }
+
+    // the mappings keep the frame alive after the handle is gone
+    let phys = frame.frame();
+    drop(frame);
+    assert_eq!(refcount::get(phys), 2);
+    unsafe { first.unmap_shared(page).unwrap() };
+    assert_eq!(refcount::get(phys), 1);
+
+    // dropping the address space removes the last mapping, which frees the frame together with the page tables
+    drop(first);
+    drop(second);
+    assert_eq!(refcount::get(phys), 0);
+    assert_eq!(free_frames(), free_before);
+}
+
+#[test_case]
+fn unmap_range_drops_one_reference() {
+    let vma = KERNEL_VMAS
+        .lock()
+        .reserve_anywhere("shared", 2 * 4096, 4096)
+        .unwrap();
+    let first: Page = Page::containing_address(vma.start);
+    let second = first + 1;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+
+    let frame = SharedFrame::new().unwrap();
+    let phys = frame.frame();
+    {
+        let mut mapper = memory::MAPPER.lock();
+        let mapper = mapper.as_mut().unwrap();
+        unsafe {
+            frame.map(mapper, first, flags).unwrap();
+            frame.map(mapper, second, flags).unwrap();
+        }
+    }
+    drop(frame);
+    assert_eq!(refcount::get(phys), 2);
+
+    let mut mapper = memory::MAPPER.lock();
+    let mapper = mapper.as_mut().unwrap();
+    unsafe {
+        memory::unmap_range(
+            mapper,
+            Page::range(first, second),
+            &mut GlobalFrameAllocator,
+        )
+        .unwrap();
+    }
+    assert_eq!(refcount::get(phys), 1);
+    // the frame is still there for the other mapping
+    let ptr: *mut u64 = second.start_address().as_mut_ptr();
+    unsafe {
+        ptr.write_volatile(1);
+        assert_eq!(ptr.read_volatile(), 1);
+    }
+
+    let free_before = free_frames();
+    unsafe {
+        memory::unmap_range(
+            mapper,
+            Page::range(second, second + 1),
+            &mut GlobalFrameAllocator,
+        )
+        .unwrap();
+    }
+    assert_eq!(refcount::get(phys), 0);
+    assert!(free_frames() > free_before);
+}
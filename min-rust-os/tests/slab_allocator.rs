@@ -0,0 +1,88 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(min_rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use min_rust_os::allocator::slab::Cache;
+use min_rust_os::memory;
+use min_rust_os::test_support;
+
+min_rust_os::test_entry_point!(test_support::init_memory);
+
+fn free_frames() -> usize {
+    memory::FRAME_ALLOCATOR
+        .lock()
+        .as_ref()
+        .unwrap()
+        .free_frames()
+}
+
+#[test_case]
+fn objects_are_not_rounded_up() {
+    // a power of two block allocator would use 32 bytes for this
+    let mut cache: Cache<[u64; 3]> = Cache::new();
+    let first = cache.alloc([1, 2, 3]).unwrap();
+    let second = cache.alloc([4, 5, 6]).unwrap();
+    assert_eq!(second.as_ptr() as usize - first.as_ptr() as usize, 24);
+    unsafe {
+        assert_eq!(*first.as_ptr(), [1, 2, 3]);
+        assert_eq!(*second.as_ptr(), [4, 5, 6]);
+        cache.free(first);
+        cache.free(second);
+    }
+}
+
+#[test_case]
+fn slabs_move_between_lists() {
+    let free_before = free_frames();
+    let mut cache: Cache<u64> = Cache::new();
+    let per_slab = Cache::<u64>::OBJECTS_PER_SLAB;
+
+    // fill one slab completely and start a second one
+    let mut objects = [None; 1024];
+    for (i, object) in objects.iter_mut().take(per_slab + 1).enumerate() {
+        *object = cache.alloc(i as u64);
+    }
+    let stats = cache.stats();
+    assert_eq!(stats.full_slabs, 1);
+    assert_eq!(stats.partial_slabs, 1);
+    assert_eq!(stats.objects_in_use, per_slab + 1);
+    assert_eq!(free_frames(), free_before - 2);
+
+    // freeing one object of the full slab makes it partial
+    unsafe { cache.free(objects[0].take().unwrap()) };
+    assert_eq!(cache.stats().full_slabs, 0);
+    assert_eq!(cache.stats().partial_slabs, 2);
+
+    // one empty slab is kept, the other goes back to the frame allocator
+    for object in objects.iter_mut() {
+        if let Some(ptr) = object.take() {
+            unsafe { cache.free(ptr) };
+        }
+    }
+    let stats = cache.stats();
+    assert_eq!(stats.empty_slabs, 1);
+    assert_eq!(stats.objects_in_use, 0);
+    assert_eq!(free_frames(), free_before - 1);
+
+    assert_eq!(cache.shrink(), 1);
+    assert_eq!(free_frames(), free_before);
+}
+
+#[test_case]
+fn free_drops_the_object() {
+    static DROPPED: AtomicUsize = AtomicUsize::new(0);
+    struct Object(u32);
+    impl Drop for Object {
+        fn drop(&mut self) {
+            DROPPED.fetch_add(self.0 as usize, Ordering::SeqCst);
+        }
+    }
+
+    let mut cache = Cache::new();
+    let object = cache.alloc(Object(7)).unwrap();
+    unsafe { cache.free(object) };
+    assert_eq!(DROPPED.load(Ordering::SeqCst), 7);
+}
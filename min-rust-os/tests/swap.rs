@@ -0,0 +1,121 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(min_rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use bootloader::BootInfo;
+use min_rust_os::memory::{
+    self,
+    swap::{self, RamDisk},
+    vma::KERNEL_VMAS,
+    GlobalFrameAllocator,
+};
+use min_rust_os::test_support;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Page, PageTableFlags, PhysFrame,
+};
+use x86_64::PhysAddr;
+
+min_rust_os::test_entry_point!(setup);
+
+fn setup(boot_info: &'static BootInfo) {
+    test_support::init_memory_and_heap(boot_info);
+    swap::init(Box::new(RamDisk::new(SWAP_SLOTS).unwrap()));
+}
+
+const SWAP_SLOTS: usize = 128;
+// Frames left to the pageable region while the rest of memory is taken
+const FREE_FRAMES: usize = 32;
+// More pages than there are free frames, but fewer than free frames and swap slots together
+const PAGES: u64 = 96;
+
+fn free_frames() -> usize {
+    memory::FRAME_ALLOCATOR
+        .lock()
+        .as_ref()
+        .unwrap()
+        .free_frames()
+}
+
+fn frame_ptr(frame: PhysFrame) -> *mut u64 {
+    (memory::physical_memory_offset() + frame.start_address().as_u64()).as_mut_ptr()
+}
+
+// Takes all but `keep` free frames away, to simulate a machine with very little memory. The taken frames are
+// chained together through their first word, since there is no room on the heap to remember them.
+fn hog_frames(keep: usize) -> Option<PhysFrame> {
+    let mut chain: Option<PhysFrame> = None;
+    while free_frames() > keep {
+        let frame: PhysFrame = GlobalFrameAllocator.allocate_frame().unwrap();
+        let next = chain.map_or(0, |frame| frame.start_address().as_u64());
+        unsafe { frame_ptr(frame).write(next) };
+        chain = Some(frame);
+    }
+    chain
+}
+
+fn release_frames(mut chain: Option<PhysFrame>) {
+    while let Some(frame) = chain {
+        let next = unsafe { frame_ptr(frame).read() };
+        unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+        chain = match next {
+            0 => None,
+            next => Some(PhysFrame::containing_address(PhysAddr::new(next))),
+        };
+    }
+}
+
+#[test_case]
+fn overcommitted_pages_survive_swapping() {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let vma = KERNEL_VMAS
+        .lock()
+        .reserve_on_demand("overcommit", PAGES * 4096, 4096, flags)
+        .unwrap();
+    let page_ptr = |i: u64| -> *mut u64 { (vma.start + i * 4096).as_mut_ptr() };
+
+    let hogged = hog_frames(FREE_FRAMES);
+    for i in 0..PAGES {
+        unsafe { page_ptr(i).write_volatile(i * 3 + 1) };
+    }
+    // not all pages fit into memory, so some of them must be on the swap device
+    assert!(swap::used_slots() > 0);
+
+    for i in 0..PAGES {
+        assert_eq!(unsafe { page_ptr(i).read_volatile() }, i * 3 + 1);
+    }
+    release_frames(hogged);
+
+    // unmapping the region frees the swap slots of the pages that are still swapped out
+    let mut mapper = memory::MAPPER.lock();
+    let pages = Page::range(
+        Page::containing_address(vma.start),
+        Page::containing_address(vma.end()),
+    );
+    unsafe { memory::unmap_range(mapper.as_mut().unwrap(), pages, &mut GlobalFrameAllocator) }
+        .unwrap();
+    assert_eq!(swap::used_slots(), 0);
+}
+
+#[test_case]
+fn untouched_pages_are_dropped() {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let vma = KERNEL_VMAS
+        .lock()
+        .reserve_on_demand("read only use", PAGES * 4096, 4096, flags)
+        .unwrap();
+    let page_ptr = |i: u64| -> *const u64 { (vma.start + i * 4096).as_ptr() };
+    let slots_before = swap::used_slots();
+
+    let hogged = hog_frames(FREE_FRAMES);
+    // Reading maps zero pages that are never dirty, so they can be evicted without using swap
+    for i in 0..PAGES {
+        assert_eq!(unsafe { page_ptr(i).read_volatile() }, 0);
+    }
+    assert_eq!(swap::used_slots(), slots_before);
+    release_frames(hogged);
+}
@@ -0,0 +1,136 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(min_rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::BootInfo;
+use min_rust_os::memory::{self, cow, refcount, vma::KERNEL_VMAS, GlobalFrameAllocator};
+use min_rust_os::test_support;
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB, Translate,
+};
+use x86_64::VirtAddr;
+
+min_rust_os::test_entry_point!(setup);
+
+fn setup(boot_info: &'static BootInfo) {
+    test_support::init_memory(boot_info);
+    refcount::init();
+}
+
+fn free_frames() -> usize {
+    memory::FRAME_ALLOCATOR
+        .lock()
+        .as_ref()
+        .unwrap()
+        .free_frames()
+}
+
+fn is_mapped(addr: VirtAddr) -> bool {
+    memory::MAPPER
+        .lock()
+        .as_ref()
+        .unwrap()
+        .translate_addr(addr)
+        .is_some()
+}
+
+#[test_case]
+fn freed_stack_returns_all_frames() {
+    // The first stack might need a new level 3 table, which is never freed
+    let top = memory::alloc_kernel_stack(4).unwrap();
+    unsafe { memory::free_kernel_stack(top) };
+
+    let free_before = free_frames();
+    let top = memory::alloc_kernel_stack(4).unwrap();
+    assert!(free_frames() < free_before);
+    unsafe { memory::free_kernel_stack(top) };
+    // the stack pages and the page tables that were only needed for them are back
+    assert_eq!(free_frames(), free_before);
+    assert!(!is_mapped(top - 1u64));
+    assert_eq!(KERNEL_VMAS.lock().find(top - 1u64), None);
+}
+
+#[test_case]
+fn large_ranges_are_unmapped() {
+    // More pages than are flushed one by one, with a hole in the middle
+    const PAGES: u64 = 100;
+    let vma = KERNEL_VMAS
+        .lock()
+        .reserve_anywhere("unmap", PAGES * 4096, 2 * 1024 * 1024)
+        .unwrap();
+    let first: Page = Page::containing_address(vma.start);
+    let free_before = free_frames();
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    {
+        let mut mapper = memory::MAPPER.lock();
+        let mapper = mapper.as_mut().unwrap();
+        for page in Page::range(first, first + PAGES).filter(|&page| page != first + 50) {
+            let frame = GlobalFrameAllocator.allocate_frame().unwrap();
+            unsafe {
+                mapper
+                    .map_to(page, frame, flags, &mut GlobalFrameAllocator)
+                    .unwrap()
+                    .flush()
+            };
+            // make sure the translation is cached
+            let _ = unsafe { page.start_address().as_ptr::<u8>().read_volatile() };
+        }
+        unsafe {
+            memory::unmap_range(
+                mapper,
+                Page::range(first, first + PAGES),
+                &mut GlobalFrameAllocator,
+            )
+            .unwrap()
+        };
+    }
+
+    for page in Page::range(first, first + PAGES) {
+        assert!(!is_mapped(page.start_address()));
+    }
+    assert_eq!(free_frames(), free_before);
+}
+
+#[test_case]
+fn shared_frames_are_freed_last() {
+    let vma = KERNEL_VMAS
+        .lock()
+        .reserve_anywhere("unmap cow", 2 * 4096, 4096)
+        .unwrap();
+    let first: Page = Page::containing_address(vma.start);
+    let frame: PhysFrame<Size4KiB> = GlobalFrameAllocator.allocate_frame().unwrap();
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let mut mapper = memory::MAPPER.lock();
+    let mapper = mapper.as_mut().unwrap();
+    unsafe {
+        cow::map_cow(mapper, first, frame, flags).unwrap();
+        cow::map_cow(mapper, first + 1, frame, flags).unwrap();
+    }
+
+    let free_before = free_frames();
+    unsafe {
+        memory::unmap_range(
+            mapper,
+            Page::range(first, first + 1),
+            &mut GlobalFrameAllocator,
+        )
+    }
+    .unwrap();
+    // the second page still uses the frame
+    assert_eq!(free_frames(), free_before);
+    assert_eq!(refcount::get(frame), 1);
+
+    unsafe {
+        memory::unmap_range(
+            mapper,
+            Page::range(first + 1, first + 2),
+            &mut GlobalFrameAllocator,
+        )
+    }
+    .unwrap();
+    assert!(free_frames() > free_before);
+    assert_eq!(refcount::get(frame), 0);
+}